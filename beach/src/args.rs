@@ -113,6 +113,7 @@ parse_arg!(u8);
 parse_arg!(u16);
 parse_arg!(u32);
 parse_arg!(u64);
+parse_arg!(usize);
 parse_arg!(String);
 
 impl ParseArg for BlockNumber {
@@ -138,6 +139,64 @@ impl ParseArg for INodeFlags {
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseFlagError {
+    flag: &'static str
+}
+
+macro_rules! flag {
+    ($(#[$attr:meta])* $name:ident, $flag:expr) => {
+        $(#[$attr])*
+        #[derive(Clone, Debug, PartialEq)]
+        pub struct $name;
+
+        impl ParseArg for $name {
+            type Err = ParseFlagError;
+            fn parse_arg(args: &mut Args) -> Result<Self, Err<Self::Err>> {
+                let arg = args.pop()?;
+                if arg == $flag {
+                    Ok($name)
+                } else {
+                    Err(Err::Other(ParseFlagError { flag: $flag }))
+                }
+            }
+        }
+    };
+}
+
+flag!(
+    /// The `-s` switch of `ls`
+    SizeFlag, "-s"
+);
+flag!(
+    /// The `-r` switch of `fsck`
+    RepairFlag, "-r"
+);
+flag!(
+    /// The `-e` switch of `newfs` that picks extent mapped inodes
+    ExtentsFlag, "-e"
+);
+
+/// Where `newfs` lays out a file system, either `<name> <block_count>` for a device file or
+/// `--ram <block_count>` for a RAM disk
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceArg {
+    File(String, u64),
+    Ram(u64)
+}
+
+impl ParseArg for DeviceArg {
+    type Err = ParseIntError;
+    fn parse_arg(args: &mut Args) -> Result<Self, Err<Self::Err>> {
+        let arg = args.pop()?;
+        if arg == "--ram" {
+            u64::parse_arg(args).map(DeviceArg::Ram)
+        } else {
+            u64::parse_arg(args).map(|block_count| DeviceArg::File(arg, block_count))
+        }
+    }
+}
+
 impl ParseArg for PathBuf {
     type Err = Void;
     fn parse_arg(args: &mut Args) -> Result<Self, Err<Self::Err>> {
@@ -191,6 +250,12 @@ impl Explain for ParseINodeFlagsError {
     }
 }
 
+impl Explain for ParseFlagError {
+    fn explain(&self, prog: &str) -> String {
+        format!("{} only understands the flag {}", prog, self.flag)
+    }
+}
+
 impl Explain for CNil {
     fn explain(&self, _: &str) -> String {
         match *self { }
@@ -253,4 +318,32 @@ mod tests {
             Ok(hlist!["foobar".to_string(), 2, None, "not-a-number".to_string()])
         );
     }
+
+    #[test]
+    fn parse_device() {
+        let args = Args::new(vec!["--ram".to_string(), "1024".to_string()]);
+        assert_eq!(
+            <Hlist![DeviceArg, Option<u16>]>::parse(args),
+            Ok(hlist![DeviceArg::Ram(1024), None])
+        );
+        let args = Args::new(vec!["disk".to_string(), "512".to_string(), "128".to_string()]);
+        assert_eq!(
+            <Hlist![DeviceArg, Option<u16>]>::parse(args),
+            Ok(hlist![DeviceArg::File("disk".to_string(), 512), Some(128)])
+        );
+    }
+
+    #[test]
+    fn parse_flag() {
+        let args = Args::new(vec!["-s".to_string(), "projects".to_string()]);
+        assert_eq!(
+            <Hlist![Option<SizeFlag>, Option<String>]>::parse(args),
+            Ok(hlist![Some(SizeFlag), Some("projects".to_string())])
+        );
+        let args = Args::new(vec!["projects".to_string()]);
+        assert_eq!(
+            <Hlist![Option<SizeFlag>, Option<String>]>::parse(args),
+            Ok(hlist![None, Some("projects".to_string())])
+        );
+    }
 }
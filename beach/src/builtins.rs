@@ -1,18 +1,28 @@
 use std::io::{self, Write};
-use std::cell::RefCell;
-use std::path::PathBuf;
+use std::cell::{Cell, RefCell};
+use std::path::{Path, PathBuf};
 use std::env::current_dir;
+use std::fs::File;
+use std::process::{Child, ChildStdout};
 
 use umbrella::BlockNumber;
-use umbrella::device::BlockDevice;
-use umbrella::fs::{INodeFlags, FileSystem, Mount};
+use umbrella::device::{self, BlockDevice, FileDevice, MemoryDevice, Error};
+use umbrella::fs::{INodeFlags, INodeFormat, FileSystem, Mount, open_image};
+use umbrella::dir::{ROOT_INODE};
+use umbrella::shared::{SharedFileSystem};
 
-use args::{Args, Parse};
+use args::{Args, Parse, DeviceArg, SizeFlag, RepairFlag, ExtentsFlag};
 
 /// The mutable state that backs a shell (environment variables, current directory, ...)
 pub struct Env {
     current_dir: RefCell<PathBuf>,
-    current_fs:  RefCell<Option<FileSystem>>
+    current_fs:  RefCell<Option<SharedFileSystem>>,
+    // The image the mounted file system was read from, none for a RAM disk
+    fs_image:    RefCell<Option<PathBuf>>,
+    fs_dir:      Cell<usize>,
+    pipe:        RefCell<Option<ChildStdout>>,
+    // The left sides of the pipes that feed `pipe`, waited on once the pipeline is done
+    writers:     RefCell<Vec<Child>>
 }
 
 impl Env {
@@ -20,7 +30,11 @@ impl Env {
         let dir = current_dir().expect("ERROR: Insufficient permissions to read master process current directory");
         Env {
             current_dir: RefCell::new(dir),
-            current_fs:  RefCell::new(None)
+            current_fs:  RefCell::new(None),
+            fs_image:    RefCell::new(None),
+            fs_dir:      Cell::new(ROOT_INODE),
+            pipe:        RefCell::new(None),
+            writers:     RefCell::new(vec![])
         }
     }
 
@@ -28,13 +42,69 @@ impl Env {
         self.current_dir.borrow().clone()
     }
 
+    /// The inode of the working directory inside of the mounted file system
+    pub fn fs_dir(&self) -> usize {
+        self.fs_dir.get()
+    }
+
+    /// Hands the output of the left side of a pipe to whatever runs next
+    pub fn set_pipe(&self, stdout: ChildStdout, writer: Child) {
+        *self.pipe.borrow_mut() = Some(stdout);
+        self.writers.borrow_mut().push(writer)
+    }
+
+    /// The output of the left side of a pipe, if the current process is on the right side of one
+    pub fn take_pipe(&self) -> Option<ChildStdout> {
+        self.pipe.borrow_mut().take()
+    }
+
+    /// Closes the pipe if nobody took it and waits for every process that wrote into a pipe
+    pub fn wait_pipes(&self) {
+        // A writer that is still blocked on a full pipe only stops once the pipe is closed
+        self.take_pipe();
+        for mut writer in self.writers.borrow_mut().drain(..) {
+            if let Err(err) = writer.wait() {
+                eprintln!("ERROR: {}", err)
+            }
+        }
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        self.current_fs.borrow().is_some()
+    }
+
     const NO_MOUNT_MSG : &'static str = "ERROR: No file system mounted, try running newfs then mount";
 
+    fn set_fs(&self, fs: FileSystem, image: Option<PathBuf>) {
+        *self.current_fs.borrow_mut() = Some(SharedFileSystem::new(fs));
+        *self.fs_image.borrow_mut() = image;
+        self.fs_dir.set(ROOT_INODE);
+    }
+
+    /// Whether the image at `path` holds the mounted file system
+    pub fn is_mounted_image(&self, path: &Path) -> bool {
+        match (self.fs_image.borrow().as_ref(), path.canonicalize()) {
+            (Some(image), Ok(path)) => *image == path,
+            _ => false
+        }
+    }
+
     pub fn with_fs<F>(&self, f: F)
     where F: FnOnce(&mut FileSystem) -> ()
     {
-        match *self.current_fs.borrow_mut() {
-            Some(ref mut fs) => f(fs),
+        match *self.current_fs.borrow() {
+            Some(ref fs) => f(&mut fs.lock()),
+            None => eprintln!("{}", Env::NO_MOUNT_MSG)
+        }
+    }
+
+    /// Like `with_fs` but hands over the shared handle, whose `read_at` and `write_at` only lock
+    /// the file system while blocks are looked up
+    pub fn with_shared_fs<F>(&self, f: F)
+    where F: FnOnce(&SharedFileSystem)
+    {
+        match *self.current_fs.borrow() {
+            Some(ref fs) => f(fs),
             None => eprintln!("{}", Env::NO_MOUNT_MSG)
         }
     }
@@ -43,8 +113,15 @@ impl Env {
     where F: FnOnce(FileSystem) -> ()
     {
         let cur_fs = self.current_fs.replace(None);
-        match cur_fs {
-            Some(fs) => f(fs),
+        match cur_fs.map(SharedFileSystem::into_inner) {
+            Some(Ok(fs)) => {
+                self.fs_image.replace(None);
+                f(fs)
+            }
+            Some(Err(fs)) => {
+                eprintln!("ERROR: The file system is still in use");
+                *self.current_fs.borrow_mut() = Some(fs);
+            }
             None => eprintln!("{}", Env::NO_MOUNT_MSG)
         }
     }
@@ -66,27 +143,52 @@ pub fn cd(env: &Env, args: Args) {
     })
 }
 
-pub fn new_fs(_env: &Env, args: Args) {
-    type Parser = Hlist![String, u64, Option<u16>];
-    Parser::parse_explain("newfs", args, |hlist_pat![file_name, block_count, block_size]| {
-        match BlockDevice::create(&file_name, block_count, block_size) {
+fn boxed<D: BlockDevice + 'static>(device: D) -> Box<dyn BlockDevice> {
+    Box::new(device)
+}
+
+pub fn new_fs(env: &Env, args: Args) {
+    type Parser = Hlist![Option<ExtentsFlag>, DeviceArg, Option<u16>, Option<u16>];
+    Parser::parse_explain("newfs", args, |hlist_pat![extents, device_arg, block_size, inode_count]| {
+        if let DeviceArg::Ram(_) = device_arg {
+            // A RAM disk is mounted right away and must not take the place of another mount
+            if env.is_mounted() {
+                eprintln!("ERROR: A file system is already mounted, run unmount first");
+                return
+            }
+        }
+        let device = match device_arg {
+            DeviceArg::File(ref file_name, block_count) => {
+                FileDevice::create(file_name, block_count, block_size).map(boxed)
+            }
+            DeviceArg::Ram(block_count) => {
+                MemoryDevice::new(block_count, block_size).map(boxed)
+            }
+        };
+        match device {
             Ok(device) => {
-                if device.config.block_size < 128 {
+                if device.config().block_size < 128 {
                     eprintln!(
                         "ERROR: The block size must be at least 128 you gave: {}",
-                        device.config.block_size
+                        device.config().block_size
                     );
                     return
                 }
-                if device.config.block_count < 128 {
+                if device.config().block_count < 128 {
                     eprintln!(
                         "ERROR: The block count must be at least 128 you gave: {}",
-                        device.config.block_count
+                        device.config().block_count
                     );
                     return
                 }
-                let newfs = FileSystem::new(device);
-                newfs.close().unwrap_or_else(|err| {
+                let format = if extents.is_some() { INodeFormat::Extents } else { INodeFormat::Tree };
+                let newfs = FileSystem::new_with_format(device, inode_count, format);
+                let newfs = match device_arg {
+                    // Nothing else can ever mount a RAM disk so it is mounted right away
+                    DeviceArg::Ram(_) => newfs.map(|newfs| env.set_fs(newfs, None)),
+                    DeviceArg::File(..) => newfs.and_then(|newfs| newfs.close())
+                };
+                newfs.unwrap_or_else(|err| {
                     eprintln!("ERROR: Could not initialize file system: {}", err);
                 });
             }
@@ -105,15 +207,20 @@ pub fn mount(env: &Env, args: Args) {
             );
             return
         }
-        match BlockDevice::open(file_name.to_string_lossy().as_ref()) {
+        match open_image(&file_name) {
             Ok(device) => {
                 match FileSystem::read(device) {
-                    Ok(Mount { clean_mount, file_system }) => {
+                    Ok(Mount { clean_mount, file_system, replayed, backup }) => {
+                        if let Some(backup) = backup {
+                            eprintln!("WARNING: The master block is damaged, recovered it from block {}", backup)
+                        }
                         if ! clean_mount {
-                            eprintln!("WARNING: The filesystem was not properly unmounted")
+                            eprintln!("WARNING: The filesystem was not properly unmounted, try running fsck")
+                        }
+                        if replayed > 0 {
+                            eprintln!("Recovered {} block(s) from the journal", replayed)
                         }
-                        let mut cur_fs = env.current_fs.borrow_mut();
-                        *cur_fs = Some(file_system);
+                        env.set_fs(file_system, file_name.canonicalize().ok());
                     }
                     Err(err) => {
                         eprintln!("ERROR: Could not sync filesystem because {}", err)
@@ -125,6 +232,33 @@ pub fn mount(env: &Env, args: Args) {
     })
 }
 
+/// Grows or shrinks the file system on an image, in place when it is the mounted one
+pub fn resize_fs(env: &Env, args: Args) {
+    type Parser = Hlist![PathBuf, u64];
+    Parser::parse_explain("resizefs", args, |hlist_pat![file_name, block_count]| {
+        if env.is_mounted_image(&file_name) {
+            env.with_fs(|fs| {
+                if let Err(err) = fs.resize(block_count) {
+                    eprintln!("ERROR: Could not resize file system: {}", err)
+                }
+            });
+            return
+        }
+        let res = open_image(&file_name)
+            .and_then(FileSystem::read)
+            .and_then(|Mount { clean_mount, mut file_system, .. }| {
+                if ! clean_mount {
+                    eprintln!("WARNING: The filesystem was not properly unmounted, try running fsck")
+                }
+                file_system.resize(block_count)?;
+                file_system.close()
+            });
+        if let Err(err) = res {
+            eprintln!("ERROR: Could not resize file system: {}", err)
+        }
+    })
+}
+
 pub fn block_map(env: &Env, _args: Args) {
     env.with_fs(|fs| {
         print!("{}", fs.block_map);
@@ -174,10 +308,39 @@ pub fn alloc_inode(env: &Env, args: Args) {
 }
 
 pub fn free_inode(env: &Env, args: Args) {
-    type Parser = Hlist![BlockNumber];
-    Parser::parse_explain("free_inode", args, |hlist_pat![block_number]| {
+    type Parser = Hlist![usize];
+    Parser::parse_explain("free_inode", args, |hlist_pat![inode_num]| {
         env.with_fs(|fs| {
-            fs.inode_map.free(block_number)
+            let inode_count = fs.master_block.inode_count() as usize;
+            if inode_num >= inode_count {
+                eprintln!(
+                    "ERROR: There is no inode {}, the file system has {}", inode_num, inode_count
+                );
+                return
+            }
+            if inode_num == ROOT_INODE {
+                eprintln!("ERROR: The root directory can not be freed");
+                return
+            }
+            let res = if fs.is_dir(inode_num) {
+                fs.read_dir(inode_num).and_then(|entries| {
+                    if entries.iter().all(|entry| entry.name == "." || entry.name == "..") {
+                        Ok(())
+                    } else {
+                        Err(Error::NotEmpty(format!("inode {}", inode_num)))
+                    }
+                })
+            } else {
+                Ok(())
+            };
+            match res.and_then(|()| fs.free_inode(inode_num)) {
+                Ok(()) => {
+                    if inode_num == env.fs_dir() {
+                        env.fs_dir.set(ROOT_INODE)
+                    }
+                }
+                Err(err) => eprintln!("ERROR: {}", err)
+            }
         })
     })
 }
@@ -190,4 +353,197 @@ pub fn unmount(env: &Env, _args: Args) {
     })
 }
 
+pub fn sync(env: &Env, _args: Args) {
+    env.with_fs(|fs| {
+        if let Err(err) = fs.write() {
+            eprintln!("ERROR: {}", err)
+        }
+    })
+}
+
+pub fn stats(env: &Env, _args: Args) {
+    env.with_fs(|fs| print!("{}", fs.stats()))
+}
+
+pub fn mkdir(env: &Env, args: Args) {
+    type Parser = Hlist![String];
+    Parser::parse_explain("mkdir", args, |hlist_pat![path]| {
+        env.with_fs(|fs| {
+            let res = fs.resolve_parent(env.fs_dir(), &path)
+                .and_then(|(parent, name)| fs.mkdir(parent, &name));
+            if let Err(err) = res {
+                eprintln!("ERROR: {}", err)
+            }
+        })
+    })
+}
+
+pub fn rmdir(env: &Env, args: Args) {
+    type Parser = Hlist![String];
+    Parser::parse_explain("rmdir", args, |hlist_pat![path]| {
+        env.with_fs(|fs| {
+            let cwd = env.fs_dir();
+            let res = fs.resolve(cwd, &path).and_then(|dir| {
+                let (parent, name) = fs.resolve_parent(cwd, &path)?;
+                fs.rmdir(parent, &name)?;
+                Ok(dir)
+            });
+            match res {
+                Ok(dir) => {
+                    if dir == cwd {
+                        env.fs_dir.set(ROOT_INODE)
+                    }
+                }
+                Err(err) => eprintln!("ERROR: {}", err)
+            }
+        })
+    })
+}
+
+pub fn ls(env: &Env, args: Args) {
+    type Parser = Hlist![Option<SizeFlag>, Option<String>];
+    Parser::parse_explain("ls", args, |hlist_pat![sizes, path]| {
+        env.with_fs(|fs| {
+            let path = path.unwrap_or(".".to_string());
+            let res = fs.resolve(env.fs_dir(), &path).and_then(|dir| fs.read_dir(dir));
+            let entries = match res {
+                Ok(entries) => entries,
+                Err(err) => return eprintln!("ERROR: {}", err)
+            };
+            for entry in entries {
+                let suffix = if fs.is_dir(entry.inode) { "/" } else { "" };
+                if sizes.is_some() {
+                    // Allocated blocks (data and pointers) next to the logical size in bytes
+                    let length = fs.inode_map.get(entry.inode).length();
+                    match fs.allocated_blocks(entry.inode) {
+                        Ok(blocks) => println!("{:>6} {:>10} {}{}", blocks, length, entry.name, suffix),
+                        Err(err) => eprintln!("ERROR: {}", err)
+                    }
+                } else {
+                    println!("{}{}", entry.name, suffix)
+                }
+            }
+        })
+    })
+}
+
+pub fn ucd(env: &Env, args: Args) {
+    type Parser = Hlist![Option<String>];
+    Parser::parse_explain("ucd", args, |hlist_pat![path]| {
+        env.with_fs(|fs| {
+            let path = path.unwrap_or("/".to_string());
+            match fs.resolve(env.fs_dir(), &path) {
+                Ok(dir) if fs.is_dir(dir) => env.fs_dir.set(dir),
+                Ok(_) => eprintln!("ERROR: ucd requires the argument to be a directory"),
+                Err(err) => eprintln!("ERROR: {}", err)
+            }
+        })
+    })
+}
+
+pub fn upwd(env: &Env, _args: Args) {
+    env.with_fs(|fs| {
+        match fs.path_of(env.fs_dir()) {
+            Ok(path) => println!("{}", path),
+            Err(err) => eprintln!("ERROR: {}", err)
+        }
+    })
+}
+
+fn resolve_file(fs: &mut FileSystem, cwd: usize, path: &str) -> device::Result<usize> {
+    let file = fs.resolve(cwd, path)?;
+    if fs.is_dir(file) {
+        Err(Error::IsADirectory(path.to_string()))
+    } else {
+        Ok(file)
+    }
+}
+
+fn create_file(fs: &mut FileSystem, cwd: usize, path: &str) -> device::Result<usize> {
+    let (parent, name) = fs.resolve_parent(cwd, path)?;
+    fs.create(parent, &name)
+}
+
+pub fn ucat(env: &Env, args: Args) {
+    type Parser = Hlist![String];
+    Parser::parse_explain("ucat", args, |hlist_pat![path]| {
+        env.with_shared_fs(|fs| {
+            let stdout = io::stdout();
+            let mut handle = stdout.lock();
+            // The guard of `lock` has to be gone before the copy takes the lock of the file
+            let file = resolve_file(&mut fs.lock(), env.fs_dir(), &path);
+            let res = file.and_then(|file| fs.copy_out(file, &mut handle));
+            handle.flush().unwrap();
+            if let Err(err) = res {
+                eprintln!("ERROR: {}", err)
+            }
+        })
+    })
+}
+
+pub fn uwrite(env: &Env, args: Args) {
+    type Parser = Hlist![String];
+    Parser::parse_explain("uwrite", args, |hlist_pat![path]| {
+        env.with_shared_fs(|fs| {
+            let file = create_file(&mut fs.lock(), env.fs_dir(), &path);
+            let res = file.and_then(|file| {
+                match env.take_pipe() {
+                    Some(mut pipe) => fs.copy_in(file, &mut pipe),
+                    None => {
+                        let stdin = io::stdin();
+                        let mut handle = stdin.lock();
+                        fs.copy_in(file, &mut handle)
+                    }
+                }
+            });
+            if let Err(err) = res {
+                eprintln!("ERROR: {}", err)
+            }
+        })
+    })
+}
+
+pub fn import(env: &Env, args: Args) {
+    type Parser = Hlist![PathBuf, String];
+    Parser::parse_explain("import", args, |hlist_pat![host_path, path]| {
+        env.with_shared_fs(|fs| {
+            let host_path = env.current_dir().join(host_path);
+            let res = File::open(&host_path).map_err(Error::from).and_then(|mut host_file| {
+                let file = create_file(&mut fs.lock(), env.fs_dir(), &path)?;
+                fs.copy_in(file, &mut host_file)
+            });
+            if let Err(err) = res {
+                eprintln!("ERROR: {}", err)
+            }
+        })
+    })
+}
 
+pub fn export(env: &Env, args: Args) {
+    type Parser = Hlist![String, PathBuf];
+    Parser::parse_explain("export", args, |hlist_pat![path, host_path]| {
+        env.with_shared_fs(|fs| {
+            let host_path = env.current_dir().join(host_path);
+            let file = resolve_file(&mut fs.lock(), env.fs_dir(), &path);
+            let res = file.and_then(|file| {
+                let mut host_file = File::create(&host_path)?;
+                fs.copy_out(file, &mut host_file)
+            });
+            if let Err(err) = res {
+                eprintln!("ERROR: {}", err)
+            }
+        })
+    })
+}
+
+pub fn fsck(env: &Env, args: Args) {
+    type Parser = Hlist![Option<RepairFlag>];
+    Parser::parse_explain("fsck", args, |hlist_pat![repair]| {
+        env.with_fs(|fs| {
+            match fs.fsck(repair.is_some()) {
+                Ok(report) => print!("{}", report),
+                Err(err) => eprintln!("ERROR: {}", err)
+            }
+        })
+    })
+}
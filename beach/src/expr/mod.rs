@@ -13,6 +13,7 @@ pub enum Program<'a> {
     Cd,
     NewFS,
     Mount,
+    ResizeFS,
     BlockMap,
     AllocBlock,
     FreeBlock,
@@ -20,6 +21,18 @@ pub enum Program<'a> {
     AllocINode,
     FreeINode,
     Unmount,
+    Sync,
+    Stats,
+    MkDir,
+    RmDir,
+    Ls,
+    UCd,
+    UPwd,
+    UCat,
+    UWrite,
+    Import,
+    Export,
+    Fsck,
     Exit,
     Other(&'a str)
 }
@@ -31,6 +44,7 @@ impl<'a> AsRef<str> for Program<'a> {
             Cd => "cd",
             NewFS => "newfs",
             Mount => "mount",
+            ResizeFS => "resizefs",
             BlockMap => "blockmap",
             AllocBlock => "alloc_block",
             FreeBlock => "free_block",
@@ -38,12 +52,57 @@ impl<'a> AsRef<str> for Program<'a> {
             AllocINode => "alloc_inode",
             FreeINode => "free_inode",
             Unmount => "unmount",
+            Sync => "sync",
+            Stats => "stats",
+            MkDir => "mkdir",
+            RmDir => "rmdir",
+            Ls => "ls",
+            UCd => "ucd",
+            UPwd => "upwd",
+            UCat => "ucat",
+            UWrite => "uwrite",
+            Import => "import",
+            Export => "export",
+            Fsck => "fsck",
             Exit => "exit",
             Other(name) => name
         }
     }
 }
 
+impl<'a> From<&'a str> for Program<'a> {
+    fn from(name: &'a str) -> Program<'a> {
+        use self::Program::*;
+        match name {
+            "cd" => Cd,
+            "newfs" => NewFS,
+            "mount" => Mount,
+            "resizefs" => ResizeFS,
+            "blockmap" => BlockMap,
+            "alloc_block" => AllocBlock,
+            "free_block" => FreeBlock,
+            "inode_map" => INodeMap,
+            "alloc_inode" => AllocINode,
+            "free_inode" => FreeINode,
+            "unmount" => Unmount,
+            "sync" => Sync,
+            "stats" => Stats,
+            "mkdir" => MkDir,
+            "rmdir" => RmDir,
+            "ls" => Ls,
+            "ucd" => UCd,
+            "upwd" => UPwd,
+            "ucat" => UCat,
+            "uwrite" => UWrite,
+            "import" => Import,
+            "export" => Export,
+            "fsck" => Fsck,
+            "exit" => Exit,
+            other => Other(other)
+        }
+    }
+}
+
 impl<'a> AsRef<OsStr> for Program<'a> {
     fn as_ref(&self) -> &OsStr {
         let s : &str = self.as_ref();
@@ -162,22 +221,11 @@ named!(
     value!((), opt!(complete!(space)))
 );
 
+// Builtins are matched against the whole word so that programs like `lsblk` or `cdrecord`
+// are not mistaken for `ls` or `cd`.
 named!(
     program<Program>,
-    alt_complete!(
-        value!(Program::Cd,         tag_s!("cd")) |
-        value!(Program::NewFS,      tag_s!("newfs")) |
-        value!(Program::Mount,      tag_s!("mount")) |
-        value!(Program::BlockMap,   tag_s!("blockmap")) |
-        value!(Program::AllocBlock, tag_s!("alloc_block")) |
-        value!(Program::FreeBlock,  tag_s!("free_block")) |
-        value!(Program::INodeMap,   tag_s!("inode_map")) |
-        value!(Program::AllocINode, tag_s!("alloc_inode")) |
-        value!(Program::FreeINode,  tag_s!("free_inode")) |
-        value!(Program::Unmount,    tag_s!("unmount")) |
-        value!(Program::Exit,       tag_s!("exit")) |
-        map!(string, Program::Other)
-    )
+    map!(string, Program::from)
 );
 
 named!(
@@ -312,6 +360,20 @@ mod tests {
             Process { name: Program::Other("ping"), args }
         );
         total_to("ping -t 5", ping_args);
+        let lsblk = Expr::Base(
+            Process {
+                name: Program::Other("lsblk"),
+                args: Args::empty()
+            }
+        );
+        total_to("lsblk", lsblk);
+        let ls = Expr::Base(
+            Process {
+                name: Program::Ls,
+                args: Args::new(vec!["projects".to_string()])
+            }
+        );
+        total_to("ls projects", ls);
     }
 
     #[test]
@@ -3,6 +3,7 @@ use std::io;
 use std::process::{Command, Stdio, ExitStatus};
 use std::fs::File;
 
+use args::Args;
 use builtins::{self, Env};
 use expr::{Expr, Process, Program, Operator};
 
@@ -21,11 +22,23 @@ impl From<io::Error> for ProcessErr {
 
 type Result<A> = result::Result<A, ProcessErr>;
 
+fn external(env: &Env, name: &str, args: Args) -> Command {
+    let mut command = Command::new(name);
+    command
+        .args(args.vec)
+        .current_dir(env.current_dir());
+    if let Some(pipe) = env.take_pipe() {
+        command.stdin(pipe);
+    }
+    command
+}
+
 fn process(env: &Env, c: Process) -> Result<Command> {
     let prog = match c.name {
         Program::Cd => builtins::cd,
         Program::NewFS => builtins::new_fs,
         Program::Mount => builtins::mount,
+        Program::ResizeFS => builtins::resize_fs,
         Program::BlockMap => builtins::block_map,
         Program::AllocBlock => builtins::alloc_block,
         Program::FreeBlock => builtins::free_block,
@@ -33,35 +46,50 @@ fn process(env: &Env, c: Process) -> Result<Command> {
         Program::AllocINode => builtins::alloc_inode,
         Program::FreeINode => builtins::free_inode,
         Program::Unmount => builtins::unmount,
+        Program::MkDir | Program::RmDir | Program::Ls | Program::Sync if ! env.is_mounted() => {
+            // Without a mounted file system these fall through to the host's programs
+            return Ok(external(env, c.name.as_ref(), c.args))
+        }
+        Program::Sync => builtins::sync,
+        Program::Stats => builtins::stats,
+        Program::MkDir => builtins::mkdir,
+        Program::RmDir => builtins::rmdir,
+        Program::Ls => builtins::ls,
+        Program::UCd => builtins::ucd,
+        Program::UPwd => builtins::upwd,
+        Program::UCat => builtins::ucat,
+        Program::UWrite => builtins::uwrite,
+        Program::Import => builtins::import,
+        Program::Export => builtins::export,
+        Program::Fsck => builtins::fsck,
         Program::Exit => {
             return Err(ProcessErr::Exit)
         }
         Program::Other(name) => {
-            let mut command = Command::new(name);
-            command
-                .args(c.args.vec)
-                .current_dir(env.current_dir());
-            return Ok(command)
+            return Ok(external(env, name, c.args))
         }
     };
     prog(env, c.args);
+    // Builtins that do not read from a pipe simply close it
+    env.wait_pipes();
     Err(ProcessErr::Continue)
 }
 
 fn sequence(env: &Env, left: Process, op: Operator, right: Expr) -> Result<Command> {
     let mut left_command = process(env, left)?;
-    let mut right_command = expr(env, right)?;
     match op {
         Operator::Pipe => {
+            // The left side is spawned before the right side is built so that builtins on the
+            // right (like uwrite) can read from it. External commands take the pipe as stdin.
             let mut left_child = left_command.stdout(Stdio::piped()).spawn()?;
-            let left_stdout = match left_child.stdout {
+            match left_child.stdout.take() {
                 None => return Err(ProcessErr::Pipe),
-                Some(stdin) => stdin
+                Some(stdout) => env.set_pipe(stdout, left_child)
             };
-            right_command.stdin(left_stdout);
-            Ok(right_command)
+            expr(env, right)
         }
         Operator::Or => {
+            let right_command = expr(env, right)?;
             let mut left_child = left_command.spawn()?;
             let left_exit = left_child.wait()?;
             if left_exit.success() {
@@ -71,6 +99,7 @@ fn sequence(env: &Env, left: Process, op: Operator, right: Expr) -> Result<Comma
             }
         }
         Operator::And => {
+            let right_command = expr(env, right)?;
             let mut left_child = left_command.spawn()?;
             let left_exit = left_child.wait()?;
             if left_exit.success() {
@@ -98,8 +127,10 @@ fn expr(env: &Env, e: Expr) -> Result<Command> {
 }
 
 pub fn exec(env: &Env, e: Expr) -> Result<ExitStatus> {
-    let mut command = expr(env, e)?;
-    let mut child = command.spawn()?;
-    let exit_code = child.wait()?;
-    Ok(exit_code)
+    let exit_code = expr(env, e).and_then(|mut command| {
+        let mut child = command.spawn()?;
+        Ok(child.wait()?)
+    });
+    env.wait_pipes();
+    exit_code
 }
@@ -12,7 +12,7 @@ pub trait Step {
 // making creating a `BlockNumber` explicit.
 // Also the field is only visible for this crate. This means that `BlockNumber` is
 // immutable from the perspective of other libraries.
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct BlockNumber {
     pub (crate) number: u64
 }
@@ -86,7 +86,7 @@ impl<S: Copy + Eq + Step> Iterator for Sequence<S> {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct BlockOffset {
     offset: u64
 }
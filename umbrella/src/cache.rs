@@ -1,187 +1,473 @@
 use std::mem;
-use std::cell::{RefCell, Ref, RefMut};
-use std::collections::hash_map::{HashMap, Entry};
-use std::rc::Rc;
+use std::cmp::max;
+use std::fmt::{self, Display, Formatter};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use block_number::{BlockNumber};
-use device::{self, BlockDevice, Error};
+use device::{self, BlockDevice};
+use dir::{DirEntry, DIR_ENTRY_SIZE};
+use encoding::{get_u64, put_u64};
+use journal::{Transaction};
 
+/// A cached block that can be handed to other threads. Each block has a lock of its own so
+/// threads only wait for each other while they touch the same block.
 #[derive(Clone)]
 pub struct SharedVec<T> {
-    pub vec: Rc<RefCell<Vec<T>>>
+    pub vec: Arc<RwLock<Vec<T>>>
 }
 
 impl<T> SharedVec<T> {
     pub fn new(vec: Vec<T>) -> SharedVec<T> {
-        SharedVec { vec: Rc::new(RefCell::new(vec)) }
+        SharedVec { vec: Arc::new(RwLock::new(vec)) }
     }
 
-    pub fn borrow(&self) -> Ref<Vec<T>> {
-        self.vec.borrow()
+    pub fn borrow(&self) -> RwLockReadGuard<'_, Vec<T>> {
+        self.vec.read().unwrap()
     }
 
-    pub fn borrow_mut(&self) -> RefMut<Vec<T>> {
-        self.vec.borrow_mut()
+    pub fn borrow_mut(&self) -> RwLockWriteGuard<'_, Vec<T>> {
+        self.vec.write().unwrap()
     }
 }
 
-pub enum CacheEntry {
-    Block {
-        block: SharedVec<u8>
-    },
-    Pointers {
-        pointers: SharedVec<BlockNumber>
-    }
+/// A pointer block viewed as an array of little endian block numbers. It shares its bytes with
+/// the cache so the same block can be read as data through `Cache::read` too.
+#[derive(Clone)]
+pub struct Pointers {
+    block: SharedVec<u8>
 }
 
-fn to_u8<T>(v: Vec<T>) -> Vec<u8> {
-    let data = v.as_ptr();
-    let len = v.len();
-    let capacity = v.capacity();
-    let element_size = mem::size_of::<T>();
-
-    // Don't allow the current vector to be dropped
-    // (which would invalidate the memory)
-    mem::forget(v);
-    unsafe {
-        // LAST-AUDIT: mckean.kylej@gmail.com 26-04-18
-        Vec::from_raw_parts(
-            data as *mut u8,
-            len * element_size,
-            capacity * element_size,
-        )
+impl Pointers {
+    pub fn len(&self) -> usize {
+        self.block.borrow().len() / mem::size_of::<u64>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, i: usize) -> BlockNumber {
+        BlockNumber::new(get_u64(&self.block.borrow(), i * mem::size_of::<u64>()))
+    }
+
+    pub fn set(&self, i: usize, block_num: BlockNumber) {
+        put_u64(&mut self.block.borrow_mut(), i * mem::size_of::<u64>(), block_num.number)
+    }
+
+    pub fn to_vec(&self) -> Vec<BlockNumber> {
+        (0 .. self.len()).map(|i| self.get(i)).collect()
+    }
+
+    pub fn copy_from_slice(&self, pointers: &[BlockNumber]) {
+        for (i, block_num) in pointers.iter().enumerate() {
+            self.set(i, *block_num)
+        }
     }
 }
 
-unsafe fn from_u8<T>(v: Vec<u8>) -> Vec<T> {
-    // LAST-AUDIT: mckean.kylej@gmail.com 26-04-18
-    let data = v.as_ptr();
-    let len = v.len();
-    let capacity = v.capacity();
-    let element_size = mem::size_of::<T>();
-
-    // Make sure we have a proper amount of capacity
-    assert_eq!(capacity % element_size, 0);
-    // Make sure we are going to read a full chunk of stuff
-    assert_eq!(len % element_size, 0);
-
-    // Don't allow the current vector to be dropped
-    // (which would invalidate the memory)
-    mem::forget(v);
-
-    Vec::from_raw_parts(
-        data as *mut T,
-        len / element_size,
-        capacity / element_size,
-    )
+/// A directory block viewed as an array of entry slots. Like `Pointers` it shares its bytes with
+/// the cache.
+#[derive(Clone)]
+pub struct DirEntries {
+    block: SharedVec<u8>
 }
 
-impl CacheEntry {
-    pub fn bytes(&self) -> Vec<u8> {
-        use self::CacheEntry::*;
-        match *self {
-            Block { ref block } => {
-                block.borrow().clone()
-            }
-            Pointers { ref pointers } => {
-                to_u8(pointers.borrow().clone())
+impl DirEntries {
+    pub fn len(&self) -> usize {
+        self.block.borrow().len() / DIR_ENTRY_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The entry in slot `i` or `None` when the slot is free.
+    pub fn get(&self, i: usize) -> Option<DirEntry> {
+        DirEntry::decode(&self.block.borrow()[i * DIR_ENTRY_SIZE .. (i + 1) * DIR_ENTRY_SIZE])
+    }
+
+    /// Puts `entry` into slot `i`, or frees the slot when there is no entry.
+    pub fn set(&self, i: usize, entry: Option<&DirEntry>) {
+        let mut block = self.block.borrow_mut();
+        let slot = &mut block[i * DIR_ENTRY_SIZE .. (i + 1) * DIR_ENTRY_SIZE];
+        match entry {
+            Some(entry) => entry.encode(slot),
+            None => {
+                for b in slot.iter_mut() {
+                    *b = 0;
+                }
             }
         }
     }
 }
 
+fn encode_pointers(pointers: &[BlockNumber], block_size: usize) -> Vec<u8> {
+    let mut block = vec![0; block_size];
+    for (i, block_num) in pointers.iter().enumerate() {
+        put_u64(&mut block, i * mem::size_of::<u64>(), block_num.number);
+    }
+    block
+}
+
+/// Counts the I/O of a mounted file system.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub blocks_read:    u64,
+    pub blocks_written: u64,
+    pub hits:           u64,
+    pub misses:         u64
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        writeln!(f, "blocks read:    {}", self.blocks_read)?;
+        writeln!(f, "blocks written: {}", self.blocks_written)?;
+        writeln!(f, "cache hits:     {}", self.hits)?;
+        writeln!(f, "cache misses:   {}", self.misses)
+    }
+}
+
+/// The number of blocks a cache holds unless it is told otherwise.
+pub const DEFAULT_CAPACITY : usize = 1024;
+
+struct Slot {
+    block: SharedVec<u8>,
+    // Set once the block is used as a pointer block. Metadata goes through the journal.
+    metadata: bool,
+    dirty: bool,
+    used:  u64
+}
+
+impl Slot {
+    fn dirty_metadata(&self) -> bool {
+        self.dirty && self.metadata
+    }
+
+    // Dirty metadata only ever reaches the device through the journal. A block that somebody
+    // still holds a handle to may be changed through that handle.
+    fn evictable(&self) -> bool {
+        ! self.dirty_metadata() && Arc::strong_count(&self.block.vec) == 1
+    }
+}
+
+/// A write-back cache of at most `capacity` blocks. Once it is full the least recently used
+/// block is evicted, and written to the device first if it was changed. Blocks are only marked
+/// dirty by `write`, `write_pointers`, and the `_mut` readers so anything changed through a
+/// handle from `read` or `read_pointers` is lost. Every block is cached as bytes and the readers
+/// only pick how to view them.
 pub struct Cache {
-    pub (crate) entries: HashMap<BlockNumber, CacheEntry>,
-    pub (crate) device:  BlockDevice
+    entries:  HashMap<BlockNumber, Slot>,
+    // Every entry keyed by the tick it was last used at, oldest first
+    recency:  BTreeMap<u64, BlockNumber>,
+    clock:    u64,
+    capacity: usize,
+    // How many entries are dirty metadata and have to wait for the journal
+    dirty_metadata: usize,
+    hits:     u64,
+    misses:   u64,
+    pub (crate) device: Box<dyn BlockDevice>
 }
 
 impl Cache {
-    pub fn new(device: BlockDevice) -> Cache {
+    pub fn new<D: BlockDevice + 'static>(device: D) -> Cache {
+        Cache::with_capacity(device, DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity<D: BlockDevice + 'static>(device: D, capacity: usize) -> Cache {
         Cache {
             entries: HashMap::new(),
-            device
+            recency: BTreeMap::new(),
+            clock: 0,
+            capacity: max(capacity, 1),
+            dirty_metadata: 0,
+            hits: 0,
+            misses: 0,
+            device: Box::new(device)
         }
     }
 
-    pub fn read(&mut self, block_num: BlockNumber) -> device::Result<SharedVec<u8>> {
-        use self::CacheEntry::*;
-        let block_size = self.device.config.block_size as usize;
-        let Cache { ref mut entries, ref mut device } = *self;
-        match entries.entry(block_num) {
-            Entry::Occupied(o) => {
-                match *o.get() {
-                    Block { ref block } => {
-                        Ok(block.clone())
-                    }
-                    Pointers { .. } => {
-                        Err(Error::CacheInvalid)
-                    }
-                }
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Changes how many blocks the cache may hold, evicting blocks until it fits.
+    pub fn set_capacity(&mut self, capacity: usize) -> device::Result<()> {
+        self.capacity = max(capacity, 1);
+        let capacity = self.capacity;
+        self.evict(capacity)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn is_cached(&self, block_num: BlockNumber) -> bool {
+        self.entries.contains_key(&block_num)
+    }
+
+    pub fn is_dirty(&self, block_num: BlockNumber) -> bool {
+        self.entries.get(&block_num).is_some_and(|slot| slot.dirty)
+    }
+
+    /// How many pointer blocks the next commit has to journal.
+    pub fn dirty_metadata(&self) -> usize {
+        self.dirty_metadata
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            blocks_read: self.device.counters().reads,
+            blocks_written: self.device.counters().writes,
+            hits: self.hits,
+            misses: self.misses
+        }
+    }
+
+    // Looks `block_num` up for a reader and makes it the most recently used entry.
+    fn touch(&mut self, block_num: BlockNumber) -> Option<&mut Slot> {
+        let slot = match self.entries.get_mut(&block_num) {
+            Some(slot) => slot,
+            None => {
+                self.misses += 1;
+                return None
             }
-            Entry::Vacant(v) => {
-                let mut block = vec![0; block_size];
-                device.read(block_num, &mut block)?;
-                let vec = SharedVec::new(block);
-                let cache_entry = Block { block: vec.clone() };
-                v.insert(cache_entry);
-                Ok(vec)
+        };
+        self.hits += 1;
+        self.recency.remove(&slot.used);
+        self.clock += 1;
+        slot.used = self.clock;
+        self.recency.insert(self.clock, block_num);
+        Some(slot)
+    }
+
+    // Evicts the least recently used entries until at most `target` are left or none of the
+    // remaining entries can be evicted.
+    fn evict(&mut self, target: usize) -> device::Result<()> {
+        while self.entries.len() > target {
+            let victim = self.recency.values().find(|block_num| self.entries[block_num].evictable());
+            let block_num = match victim {
+                Some(block_num) => *block_num,
+                None => break
+            };
+            if self.entries[&block_num].dirty {
+                let mut bytes = self.entries[&block_num].block.borrow().clone();
+                self.device.write(block_num, &mut bytes)?;
             }
+            self.forget(block_num);
         }
+        Ok(())
     }
 
-    pub fn read_pointers(&mut self, block_num: BlockNumber)
-                         -> device::Result<SharedVec<BlockNumber>> {
-        use self::CacheEntry::*;
-        let block_size = self.device.config.block_size as usize;
-        let Cache { ref mut entries, ref mut device } = *self;
-        match entries.entry(block_num) {
-            Entry::Occupied(o) => {
-                match *o.get() {
-                    Block { .. } => {
-                        Err(Error::CacheInvalid)
-                    }
-                    Pointers { ref pointers } => {
-                        Ok(pointers.clone())
-                    }
-                }
-            }
-            Entry::Vacant(v) => {
-                let mut block = vec![0; block_size];
-                device.read(block_num, &mut block)?;
-                let pointers = unsafe {
-                    // LAST-AUDIT: mckean.kylej@gmail.com 01-05-18
-                    from_u8(block)
-                };
-                let vec = SharedVec::new(pointers);
-                let cache_entry = Pointers { pointers: vec.clone() };
-                v.insert(cache_entry);
-                Ok(vec)
+    fn insert(&mut self, block_num: BlockNumber, block: Vec<u8>, metadata: bool, dirty: bool)
+              -> device::Result<SharedVec<u8>> {
+        self.forget(block_num);
+        let room = self.capacity - 1;
+        self.evict(room)?;
+        self.clock += 1;
+        self.recency.insert(self.clock, block_num);
+        let block = SharedVec::new(block);
+        let slot = Slot { block: block.clone(), metadata, dirty, used: self.clock };
+        self.dirty_metadata += slot.dirty_metadata() as usize;
+        self.entries.insert(block_num, slot);
+        Ok(block)
+    }
+
+    fn fetch(&mut self, block_num: BlockNumber, metadata: bool, dirty: bool)
+             -> device::Result<SharedVec<u8>> {
+        if let Some(slot) = self.touch(block_num) {
+            let was = slot.dirty_metadata();
+            slot.metadata |= metadata;
+            slot.dirty |= dirty;
+            let (now, block) = (slot.dirty_metadata(), slot.block.clone());
+            self.dirty_metadata += (! was && now) as usize;
+            return Ok(block)
+        }
+        let mut block = vec![0; self.device.config().block_size as usize];
+        self.device.read(block_num, &mut block)?;
+        self.insert(block_num, block, metadata, dirty)
+    }
+
+    pub fn read(&mut self, block_num: BlockNumber) -> device::Result<SharedVec<u8>> {
+        self.fetch(block_num, false, false)
+    }
+
+    /// Like `read` but marks the block dirty so that changes made through the handle are
+    /// written back.
+    pub fn read_mut(&mut self, block_num: BlockNumber) -> device::Result<SharedVec<u8>> {
+        self.fetch(block_num, false, true)
+    }
+
+    pub fn read_pointers(&mut self, block_num: BlockNumber) -> device::Result<Pointers> {
+        let block = self.fetch(block_num, true, false)?;
+        Ok(Pointers { block })
+    }
+
+    /// Like `read_pointers` but marks the block dirty so that changes made through the handle
+    /// are journaled by the next `FileSystem::write`.
+    pub fn read_pointers_mut(&mut self, block_num: BlockNumber) -> device::Result<Pointers> {
+        let block = self.fetch(block_num, true, true)?;
+        Ok(Pointers { block })
+    }
+
+    pub fn read_entries(&mut self, block_num: BlockNumber) -> device::Result<DirEntries> {
+        let block = self.fetch(block_num, false, false)?;
+        Ok(DirEntries { block })
+    }
+
+    /// Like `read_entries` but marks the block dirty so that changes made through the handle
+    /// are written back.
+    pub fn read_entries_mut(&mut self, block_num: BlockNumber) -> device::Result<DirEntries> {
+        let block = self.fetch(block_num, false, true)?;
+        Ok(DirEntries { block })
+    }
+
+    pub fn write(&mut self, block_num: BlockNumber, block: Vec<u8>) -> device::Result<()> {
+        self.insert(block_num, block, false, true)?;
+        Ok(())
+    }
+
+    pub fn write_pointers(&mut self, block_num: BlockNumber, pointers: &[BlockNumber])
+                          -> device::Result<()> {
+        let block = encode_pointers(pointers, self.device.config().block_size as usize);
+        self.insert(block_num, block, true, true)?;
+        Ok(())
+    }
+
+    /// Marks a cached block dirty again after it was changed through a handle that was held
+    /// across a `FileSystem::write`.
+    pub fn mark_dirty(&mut self, block_num: BlockNumber) {
+        if let Some(slot) = self.entries.get_mut(&block_num) {
+            self.dirty_metadata += (slot.metadata && ! slot.dirty) as usize;
+            slot.dirty = true;
+        }
+    }
+
+    /// Drops the cached copy of a block that was freed.
+    pub fn forget(&mut self, block_num: BlockNumber) {
+        if let Some(slot) = self.entries.remove(&block_num) {
+            self.dirty_metadata -= slot.dirty_metadata() as usize;
+            self.recency.remove(&slot.used);
+        }
+    }
+
+    /// Writes every dirty data block straight to the device.
+    pub fn write_data(&mut self) -> device::Result<()> {
+        let blocks = self.entries.iter()
+            .filter(|&(_, slot)| slot.dirty && ! slot.metadata)
+            .map(|(block_number, slot)| (*block_number, slot.block.borrow().clone()))
+            .collect::<Vec<_>>();
+        self.device.write_vectored(&blocks)
+    }
+
+    /// Adds every dirty pointer block to `transaction`.
+    pub fn journal_pointers(&self, transaction: &mut Transaction) {
+        for (block_number, slot) in &self.entries {
+            if slot.dirty && slot.metadata {
+                transaction.write(*block_number, slot.block.borrow().clone())
             }
         }
     }
 
-    pub fn write_pointers(&mut self, block_num: BlockNumber, pointers: Vec<BlockNumber>) {
-        use self::CacheEntry::*;
-        let ps = Pointers { pointers: SharedVec::new(pointers) };
-        self.entries.insert(block_num, ps);
+    /// Marks every block clean once `write_data` and a commit of `journal_pointers` put them
+    /// on the device, then evicts whatever no longer fits.
+    pub fn committed(&mut self) -> device::Result<()> {
+        for slot in self.entries.values_mut() {
+            slot.dirty = false;
+        }
+        self.dirty_metadata = 0;
+        let capacity = self.capacity;
+        self.evict(capacity)
     }
 
+    /// Writes every dirty block straight to the device, bypassing the journal.
     pub fn write_all(&mut self) -> device::Result<()> {
-        for (block_number, cache_entry) in &self.entries {
-            self.device.write(*block_number, &mut cache_entry.bytes())?
+        let blocks = self.entries.iter()
+            .filter(|&(_, slot)| slot.dirty)
+            .map(|(block_number, slot)| (*block_number, slot.block.borrow().clone()))
+            .collect::<Vec<_>>();
+        self.device.write_vectored(&blocks)?;
+        for slot in self.entries.values_mut() {
+            slot.dirty = false;
         }
+        self.dirty_metadata = 0;
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use block_number::{BlockNumber};
+    use device::{BlockDevice, MemoryDevice};
+    use super::*;
+
     #[test]
-    fn as_u8() {
-        let vec : Vec<u64> = vec![0, 1, 2u64.pow(20) - 1, 3];
-        let bytes = unsafe {
-            super::from_u8(super::to_u8(vec.clone()))
-        };
-        assert_eq!(vec, bytes);
+    fn evicts_least_recently_used() {
+        let device = MemoryDevice::new(16, Some(128)).unwrap();
+        let mut cache = Cache::with_capacity(device, 2);
+        cache.read(BlockNumber::new(1)).unwrap();
+        cache.read(BlockNumber::new(2)).unwrap();
+        cache.read(BlockNumber::new(1)).unwrap();
+        cache.read(BlockNumber::new(3)).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(cache.is_cached(BlockNumber::new(1)));
+        assert!(! cache.is_cached(BlockNumber::new(2)));
+        // A handle that is still held keeps its block in the cache
+        let held = cache.read(BlockNumber::new(1)).unwrap();
+        cache.read(BlockNumber::new(4)).unwrap();
+        cache.read(BlockNumber::new(5)).unwrap();
+        assert!(cache.is_cached(BlockNumber::new(1)));
+        drop(held);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.blocks_read), (2, 5, 5));
+    }
+
+    #[test]
+    fn dirty_blocks_are_written_back() {
+        let device = MemoryDevice::new(16, Some(128)).unwrap();
+        let mut cache = Cache::with_capacity(device, 1);
+        cache.read_mut(BlockNumber::new(1)).unwrap().borrow_mut()[0] = 7;
+        cache.read(BlockNumber::new(1)).unwrap().borrow_mut()[1] = 8;
+        assert!(cache.is_dirty(BlockNumber::new(1)));
+        cache.read(BlockNumber::new(2)).unwrap();
+        assert!(! cache.is_cached(BlockNumber::new(1)));
+        let mut block = vec![0; 128];
+        cache.device.read(BlockNumber::new(1), &mut block).unwrap();
+        assert_eq!(&block[.. 2], &[7, 8]);
+        // Dirty pointer blocks wait for the journal
+        cache.write_pointers(BlockNumber::new(3), &[BlockNumber::new(9); 16]).unwrap();
+        cache.read(BlockNumber::new(4)).unwrap();
+        assert!(cache.is_dirty(BlockNumber::new(3)));
+        cache.committed().unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(! cache.is_cached(BlockNumber::new(3)));
+    }
+
+    #[test]
+    fn one_block_two_views() {
+        let device = MemoryDevice::new(16, Some(128)).unwrap();
+        let mut cache = Cache::new(device);
+        let block_num = BlockNumber::new(1);
+        cache.write_pointers(block_num, &[BlockNumber::new(0x0102), BlockNumber::new(7)]).unwrap();
+        let bytes = cache.read(block_num).unwrap();
+        assert_eq!(&bytes.borrow()[.. 9], &[2, 1, 0, 0, 0, 0, 0, 0, 7]);
+        let pointers = cache.read_pointers(block_num).unwrap();
+        assert_eq!(pointers.len(), 16);
+        bytes.borrow_mut()[8] = 9;
+        assert_eq!(pointers.get(1), BlockNumber::new(9));
+        assert_eq!(pointers.to_vec()[2 ..], [BlockNumber::new(0); 14]);
+        // The same bytes hold four directory slots
+        let entries = cache.read_entries_mut(block_num).unwrap();
+        assert_eq!(entries.len(), 4);
+        entries.set(3, Some(&DirEntry::new(5, "notes")));
+        assert_eq!(entries.get(3), Some(DirEntry::new(5, "notes")));
+        assert_eq!(bytes.borrow()[96 .. 98], [5, 0]);
+        entries.set(3, None);
+        assert_eq!(entries.get(3), None);
+        assert_eq!(pointers.get(12), BlockNumber::new(0));
     }
 }
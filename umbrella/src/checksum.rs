@@ -0,0 +1,65 @@
+use encoding::{get_u32, put_u32};
+
+/// Checksummed regions end with this many bytes holding the CRC32C of everything before them.
+pub const CHECKSUM_LEN : usize = 4;
+
+// The reflected Castagnoli polynomial.
+const POLYNOMIAL : u32 = 0x82f6_3b78;
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const TABLE : [u32; 256] = make_table();
+
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    let crc = bytes.iter().fold(!0u32, |crc, b| {
+        TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8)
+    });
+    !crc
+}
+
+/// Stores the checksum of `region` in its last `CHECKSUM_LEN` bytes.
+pub (crate) fn seal(region: &mut [u8]) {
+    let end = region.len() - CHECKSUM_LEN;
+    let crc = crc32c(&region[.. end]);
+    put_u32(region, end, crc);
+}
+
+/// Checks a region that was sealed with `seal`.
+pub (crate) fn verify(region: &[u8]) -> bool {
+    let end = region.len() - CHECKSUM_LEN;
+    get_u32(region, end) == crc32c(&region[.. end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(&[0; 32]), 0x8a91_36aa);
+    }
+
+    #[test]
+    fn seal_verify() {
+        let mut region = b"umbrella....".to_vec();
+        seal(&mut region);
+        assert!(verify(&region));
+        region[3] ^= 0x10;
+        assert!(! verify(&region));
+    }
+}
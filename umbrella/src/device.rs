@@ -1,21 +1,31 @@
 use std::mem;
 use std::str::{self, FromStr};
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::fmt::{self, Debug, Display, Formatter};
 use std::result;
-use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::io::{self, Write, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
 use std::fs::{File, OpenOptions};
 use nom::{Err, digit};
-use bincode;
 
 use block_number::{BlockNumber};
 
 pub enum Error {
     Parse(Err),
-    Bincode(Box<bincode::ErrorKind>),
     IO(io::Error),
     Size(String),
-    CacheInvalid,
+    NotFound(String),
+    NotADirectory(String),
+    IsADirectory(String),
+    NotEmpty(String),
+    Exists(String),
+    InvalidName(String),
+    Loop(String),
+    Format(String),
+    Corrupt { block: BlockNumber, kind: &'static str },
     Overflow
 }
 
@@ -29,10 +39,17 @@ impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> result::Result<(), fmt::Error> {
         match *self {
             Error::Parse(ref err)   => write!(f, "parse error: {}", err),
-            Error::Bincode(ref err) => write!(f, "(de)serialization error: {}", err),
             Error::IO(ref err)      => write!(f, "{}", err),
             Error::Size(ref err)    => write!(f, "{}", err),
-            Error::CacheInvalid     => write!(f, "cache invalid"),
+            Error::NotFound(ref name)      => write!(f, "{}: no such file or directory", name),
+            Error::NotADirectory(ref name) => write!(f, "{}: not a directory", name),
+            Error::IsADirectory(ref name)  => write!(f, "{}: is a directory", name),
+            Error::NotEmpty(ref name)      => write!(f, "{}: directory not empty", name),
+            Error::Exists(ref name)        => write!(f, "{}: already exists", name),
+            Error::InvalidName(ref name)   => write!(f, "{}: invalid name", name),
+            Error::Loop(ref name)          => write!(f, "{}: too many levels of symbolic links", name),
+            Error::Format(ref err)  => write!(f, "unsupported image: {}", err),
+            Error::Corrupt { block, kind } => write!(f, "block {}: corrupt {}", block, kind),
             Error::Overflow         => write!(f, "overflow")
         }
     }
@@ -44,12 +61,6 @@ impl From<Err> for Error {
     }
 }
 
-impl From<Box<bincode::ErrorKind>> for Error {
-    fn from(bincode_err: Box<bincode::ErrorKind>) -> Error {
-        Error::Bincode(bincode_err)
-    }
-}
-
 impl From<io::Error> for Error {
     fn from(io_err: io::Error) -> Error {
         Error::IO(io_err)
@@ -111,105 +122,494 @@ impl DeviceConfig {
     }
 }
 
-pub struct BlockDevice {
-    pub config: DeviceConfig,
-        handle: File
+/// How many blocks a device read and wrote since it was opened.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Counters {
+    pub reads:  u64,
+    pub writes: u64
 }
 
-impl BlockDevice {
-    pub fn create(path: &str, count: u64, optional_size: Option<u16>) -> Result<BlockDevice>
-    {
-        let size = optional_size.unwrap_or(1024);
-        let config = DeviceConfig::new(path)
-            .block_count(count)
-            .block_size(size);
-        if config.block_count <= 0 {
-            let err_msg = format!(
-                "create: block_count [{}] is less than 1",
-                config.block_count
-            );
-            return Err(Error::Size(err_msg))
+// The counters of a device are atomic so that reads behind `&self` can count themselves.
+#[derive(Default)]
+struct SharedCounters {
+    reads:  AtomicU64,
+    writes: AtomicU64
+}
+
+impl SharedCounters {
+    fn new(counters: Counters) -> SharedCounters {
+        SharedCounters { reads: AtomicU64::new(counters.reads), writes: AtomicU64::new(counters.writes) }
+    }
+
+    fn read(&self, count: u64) {
+        self.reads.fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn wrote(&self, count: u64) {
+        self.writes.fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> Counters {
+        Counters { reads: self.reads.load(Ordering::Relaxed), writes: self.writes.load(Ordering::Relaxed) }
+    }
+}
+
+// Sorts scattered blocks by block number and joins the ones with consecutive numbers into runs
+// that can each be written in one go. Later copies of the same block stay after earlier ones.
+fn runs(blocks: &[(BlockNumber, Vec<u8>)]) -> Vec<(BlockNumber, Vec<u8>)> {
+    let mut order = (0 .. blocks.len()).collect::<Vec<_>>();
+    order.sort_by_key(|i| blocks[*i].0);
+    let mut runs : Vec<(BlockNumber, Vec<u8>, u64)> = vec![];
+    for i in order {
+        let (block_num, ref block) = blocks[i];
+        if let Some(&mut (start, ref mut buf, ref mut count)) = runs.last_mut() {
+            if start.number + *count == block_num.number {
+                buf.extend_from_slice(block);
+                *count += 1;
+                continue
+            }
         }
-        if config.block_size <= 0 {
-            let err_msg = format!(
-                "create: block_size [{}] is less than 1",
-                config.block_size
-            );
-            return Err(Error::Size(err_msg))
+        runs.push((block_num, block.clone(), 1));
+    }
+    runs.into_iter().map(|(start, buf, _)| (start, buf)).collect()
+}
+
+/// Storage addressed in blocks of `config().block_size` bytes. Every buffer passed to `read` and
+/// `write` must be exactly one block long and the buffers of the multi-block calls must be a
+/// whole number of blocks long. Reads only need `&self` so a device can be read from several
+/// threads at once.
+pub trait BlockDevice: Send + Sync {
+    fn config(&self) -> &DeviceConfig;
+
+    fn read(&self, block_num: BlockNumber, buf: &mut [u8]) -> Result<()>;
+
+    fn write(&mut self, block_num: BlockNumber, buf: &mut [u8]) -> Result<()>;
+
+    /// Blocks until every write so far has reached the storage behind the device.
+    fn sync(&mut self) -> Result<()>;
+
+    fn counters(&self) -> Counters;
+
+    /// Grows or shrinks the device to `block_count` blocks. Blocks that are added read as zeros.
+    fn resize(&mut self, block_count: u64) -> Result<()>;
+
+    /// Reads `count` consecutive blocks starting at `start` into `buf`.
+    fn read_blocks(&self, start: BlockNumber, count: u64, buf: &mut [u8]) -> Result<()> {
+        check_run(self.config(), start, count, buf)?;
+        let block_size = self.config().block_size as usize;
+        for (i, block) in buf.chunks_mut(block_size).enumerate() {
+            self.read(BlockNumber::new(start.number + i as u64), block)?;
         }
-        let file = config.file();
+        Ok(())
+    }
+
+    /// Writes `count` consecutive blocks starting at `start` from `buf`.
+    fn write_blocks(&mut self, start: BlockNumber, count: u64, buf: &[u8]) -> Result<()> {
+        check_run(self.config(), start, count, buf)?;
+        let block_size = self.config().block_size as usize;
+        for (i, block) in buf.chunks(block_size).enumerate() {
+            self.write(BlockNumber::new(start.number + i as u64), &mut block.to_vec())?;
+        }
+        Ok(())
+    }
+
+    /// Writes blocks scattered over the device in block order, with every run of consecutive
+    /// blocks going out as a single `write_blocks`.
+    fn write_vectored(&mut self, blocks: &[(BlockNumber, Vec<u8>)]) -> Result<()> {
+        let block_size = self.config().block_size as u64;
+        for (start, buf) in runs(blocks) {
+            self.write_blocks(start, buf.len() as u64 / block_size, &buf)?;
+        }
+        Ok(())
+    }
+
+    fn block_numbers_per_block(&self) -> usize {
+        (self.config().block_size / mem::size_of::<BlockNumber>() as u16) as usize
+    }
+
+    fn block_numbers_per_level(&self, level: u8) -> usize {
+        self.block_numbers_per_block().pow(level as u32)
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for Box<D> {
+    fn config(&self) -> &DeviceConfig {
+        (**self).config()
+    }
+
+    fn read(&self, block_num: BlockNumber, buf: &mut [u8]) -> Result<()> {
+        (**self).read(block_num, buf)
+    }
+
+    fn write(&mut self, block_num: BlockNumber, buf: &mut [u8]) -> Result<()> {
+        (**self).write(block_num, buf)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        (**self).sync()
+    }
+
+    fn counters(&self) -> Counters {
+        (**self).counters()
+    }
+
+    fn resize(&mut self, block_count: u64) -> Result<()> {
+        (**self).resize(block_count)
+    }
+
+    fn read_blocks(&self, start: BlockNumber, count: u64, buf: &mut [u8]) -> Result<()> {
+        (**self).read_blocks(start, count, buf)
+    }
+
+    fn write_blocks(&mut self, start: BlockNumber, count: u64, buf: &[u8]) -> Result<()> {
+        (**self).write_blocks(start, count, buf)
+    }
+
+    fn write_vectored(&mut self, blocks: &[(BlockNumber, Vec<u8>)]) -> Result<()> {
+        (**self).write_vectored(blocks)
+    }
+}
+
+fn check_geometry(count: u64, size: u16) -> Result<()> {
+    if count == 0 {
+        let err_msg = format!("create: block_count [{}] is less than 1", count);
+        return Err(Error::Size(err_msg))
+    }
+    if size == 0 {
+        let err_msg = format!("create: block_size [{}] is less than 1", size);
+        return Err(Error::Size(err_msg))
+    }
+    Ok(())
+}
+
+// Checks that `block_num` is on the device and that `buf` holds exactly one block.
+fn check_access(config: &DeviceConfig, block_num: BlockNumber, buf: &[u8]) -> Result<()> {
+    let block_count = config.block_count;
+    if block_count <= block_num.number {
+        let err_msg = format!(
+            "create: block_count [{}] is less than the requested block number [{}]",
+            block_count,
+            block_num
+        );
+        return Err(Error::Size(err_msg))
+    }
+    let block_size = config.block_size;
+    let buf_len = buf.len();
+    if block_size as usize != buf_len {
+        let err_msg = format!(
+            "read: buffer length [{}] does not equal block_size [{}]",
+            buf_len,
+            block_size
+        );
+        return Err(Error::Size(err_msg))
+    }
+    Ok(())
+}
+
+// Checks that the `count` blocks starting at `start` are on the device and that `buf` holds
+// exactly that many blocks.
+fn check_run(config: &DeviceConfig, start: BlockNumber, count: u64, buf: &[u8]) -> Result<()> {
+    let end = start.number.saturating_add(count);
+    if config.block_count < end {
+        let err_msg = format!(
+            "access: block_count [{}] is less than the end of the requested blocks [{}]",
+            config.block_count,
+            end
+        );
+        return Err(Error::Size(err_msg))
+    }
+    if config.block_size as u64 * count != buf.len() as u64 {
+        let err_msg = format!(
+            "access: buffer length [{}] does not hold [{}] blocks of [{}] bytes",
+            buf.len(),
+            count,
+            config.block_size
+        );
+        return Err(Error::Size(err_msg))
+    }
+    Ok(())
+}
+
+/// A device backed by a file at any path on the host.
+pub struct RawDevice {
+    config:   DeviceConfig,
+    handle:   File,
+    counters: SharedCounters
+}
+
+impl RawDevice {
+    /// Creates the file at `path` if it does not exist and grows it to hold `count` blocks.
+    pub fn create<P: AsRef<Path>>(path: P, count: u64, optional_size: Option<u16>)
+                                  -> Result<RawDevice> {
+        let size = optional_size.unwrap_or(1024);
+        check_geometry(count, size)?;
+        let config = DeviceConfig {
+            path:        path.as_ref().to_path_buf(),
+            block_size:  size,
+            block_count: count
+        };
         let mut handle = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(file)?;
+            .truncate(false)
+            .open(&config.path)?;
         let seek_pos = SeekFrom::Start(config.block_size as u64 * config.block_count - 1);
         handle.seek(seek_pos)?;
-        handle.write(&mut [0])?;
-        Ok(BlockDevice { config, handle })
+        handle.write_all(&[0])?;
+        Ok(RawDevice { config, handle, counters: SharedCounters::default() })
     }
 
-    pub fn open(path: &str) -> Result<BlockDevice> {
-        let mut config = DeviceConfig::parse(path)?;
+    /// Opens the file at `path` as a device of `block_size` byte blocks. Every whole block in
+    /// the file is part of the device.
+    pub fn open<P: AsRef<Path>>(path: P, block_size: u16) -> Result<RawDevice> {
+        check_geometry(1, block_size)?;
         let handle = OpenOptions::new()
             .read(true)
             .write(true)
-            .open(config.file())?;
+            .open(path.as_ref())?;
         let file_len = handle.metadata()?.len();
-        let block_size = config.block_size;
-        config.block_count = file_len / block_size as u64;
-        Ok(BlockDevice { config, handle })
+        let config = DeviceConfig {
+            path:        path.as_ref().to_path_buf(),
+            block_size,
+            block_count: file_len / block_size as u64
+        };
+        Ok(RawDevice { config, handle, counters: SharedCounters::default() })
     }
 
-    fn seek(&mut self, block_num: BlockNumber, buf: &mut [u8]) -> Result<()> {
-        let block_count = self.config.block_count;
-        if block_count <= block_num.number {
-            let err_msg = format!(
-                "create: block_count [{}] is less than the requested block number [{}]",
-                block_count,
-                block_num
-            );
-            return Err(Error::Size(err_msg))
-        }
-        let block_size = self.config.block_size;
-        let buf_len = buf.len();
-        if block_size as usize != buf_len {
+    // Where `block_num` starts in the file.
+    fn offset(&self, block_num: BlockNumber) -> u64 {
+        block_num.number * self.config.block_size as u64
+    }
+}
+
+impl BlockDevice for RawDevice {
+    fn config(&self) -> &DeviceConfig {
+        &self.config
+    }
+
+    fn read(&self, block_num: BlockNumber, buf: &mut [u8]) -> Result<()> {
+        check_access(&self.config, block_num, buf)?;
+        self.handle.read_exact_at(buf, self.offset(block_num))?;
+        self.counters.read(1);
+        Ok(())
+    }
+
+    fn write(&mut self, block_num: BlockNumber, buf: &mut [u8]) -> Result<()> {
+        check_access(&self.config, block_num, buf)?;
+        self.handle.write_all_at(buf, self.offset(block_num))?;
+        self.counters.wrote(1);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.handle.sync_data()?;
+        Ok(())
+    }
+
+    fn counters(&self) -> Counters {
+        self.counters.get()
+    }
+
+    fn resize(&mut self, block_count: u64) -> Result<()> {
+        check_geometry(block_count, self.config.block_size)?;
+        self.handle.set_len(block_count * self.config.block_size as u64)?;
+        self.config.block_count = block_count;
+        Ok(())
+    }
+
+    fn read_blocks(&self, start: BlockNumber, count: u64, buf: &mut [u8]) -> Result<()> {
+        check_run(&self.config, start, count, buf)?;
+        self.handle.read_exact_at(buf, self.offset(start))?;
+        self.counters.read(count);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: BlockNumber, count: u64, buf: &[u8]) -> Result<()> {
+        check_run(&self.config, start, count, buf)?;
+        self.handle.write_all_at(buf, self.offset(start))?;
+        self.counters.wrote(count);
+        Ok(())
+    }
+}
+
+/// A device stored in a file named `<name>.<block_size>.dev` so that it can be opened again
+/// from the file name alone.
+pub struct FileDevice {
+    config: DeviceConfig,
+    raw:    RawDevice
+}
+
+impl FileDevice {
+    pub fn create(path: &str, count: u64, optional_size: Option<u16>) -> Result<FileDevice> {
+        let size = optional_size.unwrap_or(1024);
+        let config = DeviceConfig::new(path)
+            .block_count(count)
+            .block_size(size);
+        let raw = RawDevice::create(config.file(), count, Some(size))?;
+        Ok(FileDevice { config, raw })
+    }
+
+    pub fn open(path: &str) -> Result<FileDevice> {
+        let mut config = DeviceConfig::parse(path)?;
+        let raw = RawDevice::open(config.file(), config.block_size)?;
+        config.block_count = raw.config.block_count;
+        Ok(FileDevice { config, raw })
+    }
+}
+
+impl BlockDevice for FileDevice {
+    fn config(&self) -> &DeviceConfig {
+        &self.config
+    }
+
+    fn read(&self, block_num: BlockNumber, buf: &mut [u8]) -> Result<()> {
+        self.raw.read(block_num, buf)
+    }
+
+    fn write(&mut self, block_num: BlockNumber, buf: &mut [u8]) -> Result<()> {
+        self.raw.write(block_num, buf)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.raw.sync()
+    }
+
+    fn counters(&self) -> Counters {
+        self.raw.counters()
+    }
+
+    fn resize(&mut self, block_count: u64) -> Result<()> {
+        self.raw.resize(block_count)?;
+        self.config.block_count = block_count;
+        Ok(())
+    }
+
+    fn read_blocks(&self, start: BlockNumber, count: u64, buf: &mut [u8]) -> Result<()> {
+        self.raw.read_blocks(start, count, buf)
+    }
+
+    fn write_blocks(&mut self, start: BlockNumber, count: u64, buf: &[u8]) -> Result<()> {
+        self.raw.write_blocks(start, count, buf)
+    }
+}
+
+/// The most bytes a RAM disk may hold.
+pub const MAX_MEMORY_BYTES : u64 = 1 << 30;
+
+// Makes sure a RAM disk of `count` blocks of `size` bytes can be allocated at all.
+fn check_memory(count: u64, size: u16) -> Result<()> {
+    check_geometry(count, size)?;
+    match count.checked_mul(size as u64) {
+        Some(bytes) if bytes <= MAX_MEMORY_BYTES => Ok(()),
+        _ => {
             let err_msg = format!(
-                "read: buffer length [{}] does not equal block_size [{}]",
-                buf_len,
-                block_size
+                "create: a RAM disk of [{}] blocks of [{}] bytes is larger than [{}] bytes",
+                count,
+                size,
+                MAX_MEMORY_BYTES
             );
-            return Err(Error::Size(err_msg))
+            Err(Error::Size(err_msg))
         }
-        let seek_pos = SeekFrom::Start(block_num.number * self.config.block_size as u64);
-        self.handle.seek(seek_pos)?;
+    }
+}
+
+/// A RAM disk. Clones share the same blocks so a file system can be closed and then mounted
+/// again from a clone, and everything on it is gone once the last clone is dropped. A clone is
+/// as big as the shared blocks are when it is made.
+pub struct MemoryDevice {
+    config:   DeviceConfig,
+    blocks:   Arc<RwLock<Vec<u8>>>,
+    counters: SharedCounters
+}
+
+impl MemoryDevice {
+    pub fn new(count: u64, optional_size: Option<u16>) -> Result<MemoryDevice> {
+        let size = optional_size.unwrap_or(1024);
+        check_memory(count, size)?;
+        let config = DeviceConfig::new("memory")
+            .block_count(count)
+            .block_size(size);
+        let blocks = Arc::new(RwLock::new(vec![0; size as usize * count as usize]));
+        Ok(MemoryDevice { config, blocks, counters: SharedCounters::default() })
+    }
+
+    fn range(&self, block_num: BlockNumber, buf: &[u8]) -> Result<Range<usize>> {
+        check_access(&self.config, block_num, buf)?;
+        let start = block_num.index() * self.config.block_size as usize;
+        Ok(start .. start + buf.len())
+    }
+
+    fn run_range(&self, start: BlockNumber, count: u64, buf: &[u8]) -> Result<Range<usize>> {
+        check_run(&self.config, start, count, buf)?;
+        let start = start.index() * self.config.block_size as usize;
+        Ok(start .. start + buf.len())
+    }
+}
+
+impl Clone for MemoryDevice {
+    fn clone(&self) -> MemoryDevice {
+        let len = self.blocks.read().unwrap().len() as u64;
+        let config = self.config.clone().block_count(len / self.config.block_size as u64);
+        let counters = SharedCounters::new(self.counters.get());
+        MemoryDevice { config, blocks: self.blocks.clone(), counters }
+    }
+}
+
+impl BlockDevice for MemoryDevice {
+    fn config(&self) -> &DeviceConfig {
+        &self.config
+    }
+
+    fn read(&self, block_num: BlockNumber, buf: &mut [u8]) -> Result<()> {
+        let range = self.range(block_num, buf)?;
+        buf.copy_from_slice(&self.blocks.read().unwrap()[range]);
+        self.counters.read(1);
         Ok(())
     }
 
-    pub fn read(&mut self, block_num: BlockNumber, buf: &mut [u8]) -> Result<()> {
-        self.seek(block_num, buf)?;
-        self.handle.read_exact(buf)?;
+    fn write(&mut self, block_num: BlockNumber, buf: &mut [u8]) -> Result<()> {
+        let range = self.range(block_num, buf)?;
+        self.blocks.write().unwrap()[range].copy_from_slice(buf);
+        self.counters.wrote(1);
         Ok(())
     }
 
-    pub fn write(&mut self, block_num: BlockNumber, buf: &mut [u8]) -> Result<()> {
-        self.seek(block_num, buf)?;
-        self.handle.write_all(buf)?;
+    fn sync(&mut self) -> Result<()> {
         Ok(())
     }
 
-    pub fn block_numbers_per_block(&self) -> usize {
-        (self.config.block_size / mem::size_of::<BlockNumber>() as u16) as usize
+    fn counters(&self) -> Counters {
+        self.counters.get()
     }
 
-    pub fn block_numbers_per_level(&self, level: u8) -> usize {
-        self.block_numbers_per_block().pow(level as u32)
+    fn resize(&mut self, block_count: u64) -> Result<()> {
+        check_memory(block_count, self.config.block_size)?;
+        let len = block_count as usize * self.config.block_size as usize;
+        self.blocks.write().unwrap().resize(len, 0);
+        self.config.block_count = block_count;
+        Ok(())
+    }
+
+    fn read_blocks(&self, start: BlockNumber, count: u64, buf: &mut [u8]) -> Result<()> {
+        let range = self.run_range(start, count, buf)?;
+        buf.copy_from_slice(&self.blocks.read().unwrap()[range]);
+        self.counters.read(count);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: BlockNumber, count: u64, buf: &[u8]) -> Result<()> {
+        let range = self.run_range(start, count, buf)?;
+        self.blocks.write().unwrap()[range].copy_from_slice(buf);
+        self.counters.wrote(count);
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
 
+    use std::{env, fs, process, thread};
     use std::fmt::{Debug};
     use nom::{IResult};
 
@@ -245,13 +645,71 @@ mod tests {
         assert_eq!(file, PathBuf::from("mydev.1024.dev"))
     }
 
-    #[test]
-    fn block_write_read() {
-        let mut block_device = BlockDevice::create("mydev", 16, Some(128)).unwrap();
+    fn write_read<D: BlockDevice>(mut block_device: D) {
         let mut nums = (0..128).collect::<Vec<_>>();
         let mut out  = vec![0; 128];
         block_device.write(BlockNumber::new(1), nums.as_mut_slice()).unwrap();
         block_device.read(BlockNumber::new(1), out.as_mut_slice()).unwrap();
         assert_eq!(nums, out);
+        assert!(block_device.read(BlockNumber::new(16), out.as_mut_slice()).is_err());
+        assert_eq!(block_device.counters(), Counters { reads: 1, writes: 1 });
+    }
+
+    fn multi_block<D: BlockDevice>(mut block_device: D) {
+        let nums = (0 .. 3 * 128).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut out = vec![0; 3 * 128];
+        block_device.write_blocks(BlockNumber::new(2), 3, &nums).unwrap();
+        block_device.read_blocks(BlockNumber::new(2), 3, &mut out).unwrap();
+        assert_eq!(nums, out);
+        assert!(block_device.read_blocks(BlockNumber::new(14), 3, &mut out).is_err());
+        assert!(block_device.write_blocks(BlockNumber::new(0), 2, &nums).is_err());
+        let blocks = [5, 3, 9, 4].iter()
+            .map(|n| (BlockNumber::new(*n), vec![*n as u8; 128]))
+            .collect::<Vec<_>>();
+        block_device.write_vectored(&blocks).unwrap();
+        block_device.read_blocks(BlockNumber::new(3), 3, &mut out).unwrap();
+        assert_eq!(&out[.. 128], &[3; 128][..]);
+        assert_eq!(&out[128 .. 256], &[4; 128][..]);
+        assert_eq!(&out[256 ..], &[5; 128][..]);
+        assert_eq!(block_device.counters(), Counters { reads: 6, writes: 7 });
+    }
+
+    fn shared_reads<D: BlockDevice>(mut block_device: D) {
+        for n in 0 .. 16 {
+            block_device.write(BlockNumber::new(n), &mut [n as u8; 128]).unwrap();
+        }
+        let device = &block_device;
+        thread::scope(|scope| {
+            for _ in 0 .. 4 {
+                scope.spawn(move || {
+                    let mut out = vec![0; 128];
+                    for n in (0 .. 16).rev() {
+                        device.read(BlockNumber::new(n), &mut out).unwrap();
+                        assert!(out.iter().all(|b| *b == n as u8));
+                    }
+                });
+            }
+        });
+        assert_eq!(block_device.counters(), Counters { reads: 64, writes: 16 });
+    }
+
+    #[test]
+    fn block_write_read() {
+        let name = env::temp_dir().join(format!("umbrella-{}", process::id()));
+        let name = name.to_string_lossy();
+        write_read(FileDevice::create(&name, 16, Some(128)).unwrap());
+        multi_block(FileDevice::open(&format!("{}.128.dev", name)).unwrap());
+        fs::remove_file(format!("{}.128.dev", name)).unwrap();
+        write_read(MemoryDevice::new(16, Some(128)).unwrap());
+        multi_block(MemoryDevice::new(16, Some(128)).unwrap());
+        shared_reads(MemoryDevice::new(16, Some(128)).unwrap());
+        assert!(MemoryDevice::new(u64::MAX / 64, Some(128)).is_err());
+        let path = env::temp_dir().join(format!("umbrella-raw-{}.img", process::id()));
+        write_read(RawDevice::create(&path, 16, Some(128)).unwrap());
+        multi_block(RawDevice::open(&path, 128).unwrap());
+        shared_reads(RawDevice::open(&path, 128).unwrap());
+        let raw = RawDevice::open(&path, 128).unwrap();
+        assert_eq!(raw.config().block_count, 16);
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,289 @@
+use std::cmp::{min};
+
+use block_number::{BlockOffset};
+use device::{self, Error};
+use encoding::{get_u16, put_u16};
+use fs::{FileSystem, INodeFlags};
+
+/// The inode number of the root directory. `FileSystem::new` allocates it before anything else.
+pub const ROOT_INODE : usize = 0;
+
+// A directory is stored in its inode's data blocks as an array of fixed size slots:
+//
+//   | inode: u16 (little endian) | name_len: u8 | reserved: u8 | name: [u8; 28] |
+//
+// A slot with a `name_len` of zero is free. Slots are read and written through the cache's
+// `DirEntries` view of the inode's data blocks, which holds a whole number of slots. The inode's
+// `length` is the number of bytes of slots in use, free slots included.
+pub const DIR_ENTRY_SIZE : usize = 32;
+pub const MAX_NAME_LEN   : usize = DIR_ENTRY_SIZE - 4;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub inode: usize,
+    pub name:  String
+}
+
+impl DirEntry {
+    pub fn new(inode: usize, name: &str) -> DirEntry {
+        DirEntry { inode, name: name.to_string() }
+    }
+
+    pub (crate) fn encode(&self, slot: &mut [u8]) {
+        let name = self.name.as_bytes();
+        put_u16(slot, 0, self.inode as u16);
+        slot[2] = name.len() as u8;
+        slot[3] = 0;
+        slot[4 .. 4 + name.len()].copy_from_slice(name);
+        for b in slot[4 + name.len() ..].iter_mut() {
+            *b = 0;
+        }
+    }
+
+    pub (crate) fn decode(slot: &[u8]) -> Option<DirEntry> {
+        let name_len = slot[2] as usize;
+        if name_len == 0 || name_len > MAX_NAME_LEN {
+            return None
+        }
+        let inode = get_u16(slot, 0) as usize;
+        let name = String::from_utf8_lossy(&slot[4 .. 4 + name_len]).into_owned();
+        Some(DirEntry { inode, name })
+    }
+}
+
+pub (crate) fn validate_name(name: &str) -> device::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/')
+        || name.len() > MAX_NAME_LEN {
+        Err(Error::InvalidName(name.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+impl FileSystem {
+    /// Allocates the root directory. The root is its own parent.
+    pub (crate) fn make_root(&mut self) -> device::Result<()> {
+        match self.inode_map.alloc(INodeFlags::DIR) {
+            Some(ROOT_INODE) => {
+                self.write_slot(ROOT_INODE, 0, Some(&DirEntry::new(ROOT_INODE, ".")))?;
+                self.write_slot(ROOT_INODE, 1, Some(&DirEntry::new(ROOT_INODE, "..")))
+            }
+            _ => Err(Error::Size("could not allocate the root inode".to_string()))
+        }
+    }
+
+    pub fn is_dir(&self, inode_num: usize) -> bool {
+        self.inode_map.get(inode_num).flags.contains(INodeFlags::DIR)
+    }
+
+    fn slot_count(&self, dir: usize) -> usize {
+        self.inode_map.get(dir).length as usize / DIR_ENTRY_SIZE
+    }
+
+    // Every slot of `dir` in order, `None` for the free ones.
+    fn read_slots(&mut self, dir: usize) -> device::Result<Vec<Option<DirEntry>>> {
+        let per_block = self.cache.device.config().block_size as usize / DIR_ENTRY_SIZE;
+        let slot_count = self.slot_count(dir);
+        let mut slots = Vec::with_capacity(slot_count);
+        for first in (0 .. slot_count).step_by(per_block) {
+            let count = min(per_block, slot_count - first);
+            let offset = BlockOffset::new((first / per_block) as u64);
+            match self.lookup_block_num_from_offset(dir, offset)? {
+                Some(block_num) => {
+                    let entries = self.cache.read_entries(block_num)?;
+                    slots.extend((0 .. count).map(|i| entries.get(i)));
+                }
+                None => slots.extend((0 .. count).map(|_| None))
+            }
+        }
+        Ok(slots)
+    }
+
+    fn write_slot(&mut self, dir: usize, slot: usize, entry: Option<&DirEntry>)
+                  -> device::Result<()> {
+        let per_block = self.cache.device.config().block_size as usize / DIR_ENTRY_SIZE;
+        let offset = (slot * DIR_ENTRY_SIZE) as u64;
+        let blocks = self.data_blocks_mut(dir, offset, DIR_ENTRY_SIZE)?;
+        self.cache.read_entries_mut(blocks[0].0)?.set(slot % per_block, entry);
+        self.wrote(dir, offset + DIR_ENTRY_SIZE as u64);
+        Ok(())
+    }
+
+    /// Lists every entry of `dir`, including `.` and `..`.
+    pub fn read_dir(&mut self, dir: usize) -> device::Result<Vec<DirEntry>> {
+        if ! self.is_dir(dir) {
+            return Err(Error::NotADirectory(format!("inode {}", dir)))
+        }
+        Ok(self.read_slots(dir)?.into_iter().flatten().collect())
+    }
+
+    /// Finds the inode number of `name` inside of `dir`.
+    pub fn lookup(&mut self, dir: usize, name: &str) -> device::Result<Option<usize>> {
+        let entries = self.read_dir(dir)?;
+        Ok(entries.into_iter().find(|entry| entry.name == name).map(|entry| entry.inode))
+    }
+
+    pub (crate) fn add_entry(&mut self, dir: usize, entry: &DirEntry) -> device::Result<()> {
+        validate_name(&entry.name)?;
+        if self.lookup(dir, &entry.name)?.is_some() {
+            return Err(Error::Exists(entry.name.clone()))
+        }
+        let slots = self.read_slots(dir)?;
+        let free_slot = slots.iter().position(|slot| slot.is_none()).unwrap_or(slots.len());
+        self.write_slot(dir, free_slot, Some(entry))
+    }
+
+    pub (crate) fn remove_entry(&mut self, dir: usize, name: &str) -> device::Result<usize> {
+        let slots = self.read_slots(dir)?;
+        for (slot, entry) in slots.into_iter().enumerate() {
+            if let Some(entry) = entry {
+                if entry.name == name {
+                    self.write_slot(dir, slot, None)?;
+                    return Ok(entry.inode)
+                }
+            }
+        }
+        Err(Error::NotFound(name.to_string()))
+    }
+
+    /// Points `entry.name` in `dir` at `entry.inode`, adding the entry if it is missing.
+    /// Unlike `add_entry` this accepts `.` and `..` so it can be used to repair a directory.
+    pub (crate) fn set_entry(&mut self, dir: usize, entry: &DirEntry) -> device::Result<()> {
+        let slots = self.read_slots(dir)?;
+        let mut free_slot = None;
+        for (slot, old) in slots.iter().enumerate() {
+            match *old {
+                Some(ref old) if old.name == entry.name => {
+                    return self.write_slot(dir, slot, Some(entry))
+                }
+                None if free_slot.is_none() => free_slot = Some(slot),
+                _ => {}
+            }
+        }
+        self.write_slot(dir, free_slot.unwrap_or(slots.len()), Some(entry))
+    }
+
+    /// Creates the directory `name` inside of `parent` and returns its inode number.
+    pub fn mkdir(&mut self, parent: usize, name: &str) -> device::Result<usize> {
+        validate_name(name)?;
+        if ! self.is_dir(parent) {
+            return Err(Error::NotADirectory(format!("inode {}", parent)))
+        }
+        if self.lookup(parent, name)?.is_some() {
+            return Err(Error::Exists(name.to_string()))
+        }
+        // The new directory and its parent
+        self.make_room(2)?;
+        let dir = match self.inode_map.alloc(INodeFlags::DIR) {
+            Some(dir) => dir,
+            None => return Err(Error::Size("out of inodes".to_string()))
+        };
+        let linked = self.write_slot(dir, 0, Some(&DirEntry::new(dir, ".")))
+            .and_then(|()| self.write_slot(dir, 1, Some(&DirEntry::new(parent, ".."))))
+            .and_then(|()| self.add_entry(parent, &DirEntry::new(dir, name)));
+        match linked {
+            Ok(()) => Ok(dir),
+            Err(err) => {
+                // Nothing points at the new directory yet so it is simply given back
+                self.free_inode(dir)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Removes the empty directory `name` from `parent`.
+    pub fn rmdir(&mut self, parent: usize, name: &str) -> device::Result<()> {
+        validate_name(name)?;
+        let dir = match self.lookup(parent, name)? {
+            Some(dir) => dir,
+            None => return Err(Error::NotFound(name.to_string()))
+        };
+        if ! self.is_dir(dir) {
+            return Err(Error::NotADirectory(name.to_string()))
+        }
+        let empty = self.read_dir(dir)?
+            .iter()
+            .all(|entry| entry.name == "." || entry.name == "..");
+        if ! empty {
+            return Err(Error::NotEmpty(name.to_string()))
+        }
+        self.remove_entry(parent, name)?;
+        self.free_inode(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use device::MemoryDevice;
+    use super::*;
+
+    fn names(entries: Vec<DirEntry>) -> Vec<String> {
+        entries.into_iter().map(|entry| entry.name).collect()
+    }
+
+    #[test]
+    fn dir_entry_round_trip() {
+        let entry = DirEntry::new(300, "notes.txt");
+        let mut slot = [0xff; DIR_ENTRY_SIZE];
+        entry.encode(&mut slot);
+        assert_eq!(DirEntry::decode(&slot), Some(entry));
+    }
+
+    #[test]
+    fn root_is_empty() {
+        let device = MemoryDevice::new(128, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
+        let root = fs.read_dir(ROOT_INODE).unwrap();
+        assert_eq!(root, vec![DirEntry::new(0, "."), DirEntry::new(0, "..")]);
+    }
+
+    #[test]
+    fn mkdir_rmdir_nested() {
+        let device = MemoryDevice::new(128, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
+        let projects = fs.mkdir(ROOT_INODE, "projects").unwrap();
+        let beach = fs.mkdir(projects, "beach").unwrap();
+        for i in 0 .. 10 {
+            fs.mkdir(beach, &format!("dir{}", i)).unwrap();
+        }
+        assert_eq!(fs.lookup(ROOT_INODE, "projects").unwrap(), Some(projects));
+        assert_eq!(fs.lookup(beach, "..").unwrap(), Some(projects));
+        assert_eq!(fs.read_dir(beach).unwrap().len(), 12);
+        match fs.rmdir(ROOT_INODE, "projects") {
+            Err(Error::NotEmpty(_)) => {}
+            res => panic!("expected NotEmpty but got {:?}", res)
+        }
+        fs.rmdir(beach, "dir3").unwrap();
+        fs.mkdir(beach, "again").unwrap();
+        let entries = names(fs.read_dir(beach).unwrap());
+        assert!(entries.contains(&"again".to_string()));
+        assert!(! entries.contains(&"dir3".to_string()));
+        assert_eq!(entries.len(), 12);
+    }
+
+    #[test]
+    fn mkdir_rejects_bad_names() {
+        let device = MemoryDevice::new(128, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
+        fs.mkdir(ROOT_INODE, "a").unwrap();
+        assert!(fs.mkdir(ROOT_INODE, "a").is_err());
+        assert!(fs.mkdir(ROOT_INODE, "a/b").is_err());
+        assert!(fs.mkdir(ROOT_INODE, "..").is_err());
+        assert!(fs.mkdir(ROOT_INODE, &"x".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn failed_mkdir_frees_its_inode() {
+        let device = MemoryDevice::new(128, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
+        let file = fs.create(ROOT_INODE, "full").unwrap();
+        // The new directory gets an inode but no block for its entries
+        while fs.block_map.alloc().is_ok() {}
+        match fs.mkdir(ROOT_INODE, "nowhere") {
+            Err(Error::Size(_)) => {}
+            res => panic!("expected Size but got {:?}", res)
+        }
+        assert_eq!(fs.inode_map.get(file + 1).flags, INodeFlags::FREE);
+        assert_eq!(fs.lookup(ROOT_INODE, "nowhere").unwrap(), None);
+    }
+}
@@ -0,0 +1,37 @@
+// Helpers for reading and writing the little endian integers that make up the on-disk formats.
+// Every function takes the buffer and the byte offset of the field inside of it.
+
+macro_rules! little_endian {
+    ($get:ident, $put:ident, $t:ty, $len:expr) => {
+        pub (crate) fn $get(buf: &[u8], at: usize) -> $t {
+            let mut bytes = [0; $len];
+            bytes.copy_from_slice(&buf[at .. at + $len]);
+            <$t>::from_le_bytes(bytes)
+        }
+
+        pub (crate) fn $put(buf: &mut [u8], at: usize, value: $t) {
+            buf[at .. at + $len].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+little_endian!(get_u16, put_u16, u16, 2);
+little_endian!(get_u32, put_u32, u32, 4);
+little_endian!(get_u64, put_u64, u64, 8);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn little_endian_layout() {
+        let mut buf = [0; 14];
+        put_u16(&mut buf, 0, 0x0102);
+        put_u32(&mut buf, 2, 0x0304_0506);
+        put_u64(&mut buf, 6, 0x0708_090a_0b0c_0d0e);
+        assert_eq!(buf, [2, 1, 6, 5, 4, 3, 0xe, 0xd, 0xc, 0xb, 0xa, 9, 8, 7]);
+        assert_eq!(get_u16(&buf, 0), 0x0102);
+        assert_eq!(get_u32(&buf, 2), 0x0304_0506);
+        assert_eq!(get_u64(&buf, 6), 0x0708_090a_0b0c_0d0e);
+    }
+}
@@ -0,0 +1,476 @@
+use std::cmp::{max, min};
+
+use block_number::{BlockNumber, MASTER_BLOCK_NUMBER};
+use device::{self, Error};
+use fs::{FileSystem};
+
+// An extent maps `length` logical blocks starting at `logical` onto the device blocks starting
+// at `start`. Inodes of an extent file system keep their extents sorted by `logical` in a tree
+// whose nodes are arrays of entries that each take up two block numbers:
+//
+//   | logical: u32 | length: u32 | start: u64 |
+//
+// The inode's `block_ptrs` is the root node and holds `ROOT_ENTRIES` entries, every other node
+// is a pointer block. In a leaf (level zero) every entry is an extent. In an index node `start`
+// is the child node and `logical` is the first logical block that the child maps. Unused
+// entries have a `start` of zero. Allocating a block only changes the nodes on the path down
+// to its leaf: a full node is split in half and a full root moves down into a new node, which
+// grows the tree by a level. A file that never fragments never needs more than its inode.
+const ROOT_ENTRIES : usize = 4;
+
+/// The number of logical blocks an extent file can address.
+pub const MAX_EXTENT_BLOCKS : u64 = 1 << 32;
+const MAX_EXTENT_LEN : u64 = (1 << 32) - 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: u64,
+    pub start:   BlockNumber,
+    pub length:  u64
+}
+
+impl Extent {
+    fn end(&self) -> u64 {
+        self.logical + self.length
+    }
+
+    pub (crate) fn blocks(&self) -> Vec<BlockNumber> {
+        (0 .. self.length).map(|i| BlockNumber::new(self.start.number + i)).collect()
+    }
+}
+
+fn get_entry(node: &[BlockNumber], i: usize) -> Option<Extent> {
+    let start = node[2 * i + 1];
+    if start == MASTER_BLOCK_NUMBER {
+        return None
+    }
+    let packed = node[2 * i].number;
+    Some(Extent { logical: packed & MAX_EXTENT_LEN, start, length: packed >> 32 })
+}
+
+fn put_entry(node: &mut [BlockNumber], i: usize, extent: &Extent) {
+    node[2 * i] = BlockNumber::new(extent.logical | extent.length << 32);
+    node[2 * i + 1] = extent.start;
+}
+
+// Whether `next` continues `extent` both in the file and on the device.
+fn joins(extent: &Extent, next: &Extent) -> bool {
+    extent.end() == next.logical
+        && extent.start.number + extent.length == next.start.number
+        && extent.length + next.length <= MAX_EXTENT_LEN
+}
+
+// The index nodes above a leaf, root first, each with the entry that leads further down.
+// The root is the node without a block number.
+type Path = Vec<(Option<BlockNumber>, Vec<Extent>, usize)>;
+
+// The entry of an index node that the child `start` mapping from `logical` on hangs off.
+fn index(logical: u64, start: BlockNumber) -> Extent {
+    Extent { logical, start, length: 0 }
+}
+
+pub (crate) fn entries(node: &[BlockNumber]) -> Vec<Extent> {
+    (0 .. node.len() / 2).filter_map(|i| get_entry(node, i)).collect()
+}
+
+// Merges neighbouring extents that are contiguous both in the file and on the device.
+pub (crate) fn coalesce(extents: &mut Vec<Extent>) {
+    let mut merged : Vec<Extent> = Vec::with_capacity(extents.len());
+    for extent in extents.drain(..) {
+        if let Some(last) = merged.last_mut() {
+            if joins(last, &extent) {
+                last.length += extent.length;
+                continue
+            }
+        }
+        merged.push(extent);
+    }
+    *extents = merged;
+}
+
+impl FileSystem {
+    pub (crate) fn lookup_extent(&mut self, inode_num: usize, offset: u64)
+                                 -> device::Result<Option<BlockNumber>> {
+        let (mut node, mut level) = {
+            let inode = self.inode_map.get(inode_num);
+            (inode.block_ptrs.to_vec(), inode.level)
+        };
+        loop {
+            let found = entries(&node).into_iter().rev().find(|extent| extent.logical <= offset);
+            match found {
+                None => return Ok(None),
+                Some(extent) if level == 0 => {
+                    if offset < extent.end() {
+                        let block_num = extent.start.number + offset - extent.logical;
+                        return Ok(Some(BlockNumber::new(block_num)))
+                    }
+                    return Ok(None)
+                }
+                Some(index) => {
+                    node = self.cache.read_pointers(index.start)?.to_vec();
+                    level -= 1;
+                }
+            }
+        }
+    }
+
+    /// Returns the extents of `inode_num` in logical order along with the tree nodes that
+    /// hold them.
+    pub fn read_extents(&mut self, inode_num: usize)
+                        -> device::Result<(Vec<Extent>, Vec<BlockNumber>)> {
+        let (root, level) = {
+            let inode = self.inode_map.get(inode_num);
+            (inode.block_ptrs, inode.level)
+        };
+        let mut extents = vec![];
+        let mut nodes = vec![];
+        let mut stack = entries(&root).into_iter().rev().map(|e| (e, level)).collect::<Vec<_>>();
+        while let Some((extent, level)) = stack.pop() {
+            if level == 0 {
+                extents.push(extent);
+                continue
+            }
+            nodes.push(extent.start);
+            let children = entries(&self.cache.read_pointers(extent.start)?.to_vec());
+            stack.extend(children.into_iter().rev().map(|e| (e, level - 1)));
+        }
+        Ok((extents, nodes))
+    }
+
+    /// Replaces the extent tree of `inode_num` with one holding `extents`. The blocks in `nodes`
+    /// are reused for the new tree first and any that are left over are freed.
+    pub (crate) fn write_extents(&mut self, inode_num: usize, extents: &[Extent],
+                                 mut nodes: Vec<BlockNumber>) -> device::Result<()> {
+        let node_len = self.cache.device.block_numbers_per_block();
+        let per_node = node_len / 2;
+        nodes.reverse();
+        let mut level = 0;
+        let mut current = extents.to_vec();
+        while current.len() > ROOT_ENTRIES {
+            let mut parents = vec![];
+            for chunk in current.chunks(per_node) {
+                let block_num = match nodes.pop() {
+                    Some(block_num) => block_num,
+                    None => self.block_map.alloc()?
+                };
+                let mut node = vec![MASTER_BLOCK_NUMBER; node_len];
+                for (i, extent) in chunk.iter().enumerate() {
+                    put_entry(&mut node, i, extent);
+                }
+                self.cache.write_pointers(block_num, &node)?;
+                parents.push(Extent { logical: chunk[0].logical, start: block_num, length: 0 });
+            }
+            current = parents;
+            level += 1;
+        }
+        for block_num in nodes {
+            self.cache.forget(block_num);
+            self.block_map.free(block_num);
+        }
+        let inode = self.inode_map.get_mut(inode_num);
+        inode.block_ptrs = [MASTER_BLOCK_NUMBER; 8];
+        for (i, extent) in current.iter().enumerate() {
+            put_entry(&mut inode.block_ptrs, i, extent);
+        }
+        inode.level = level;
+        Ok(())
+    }
+
+    // The entries of `node`, which is the root when it is `None`.
+    fn read_node(&mut self, inode_num: usize, node: Option<BlockNumber>)
+                 -> device::Result<Vec<Extent>> {
+        match node {
+            Some(block_num) => Ok(entries(&self.cache.read_pointers(block_num)?.to_vec())),
+            None => Ok(entries(&self.inode_map.get(inode_num).block_ptrs))
+        }
+    }
+
+    fn write_node(&mut self, inode_num: usize, node: Option<BlockNumber>, extents: &[Extent])
+                  -> device::Result<()> {
+        match node {
+            Some(block_num) => {
+                let node_len = self.cache.device.block_numbers_per_block();
+                let mut block = vec![MASTER_BLOCK_NUMBER; node_len];
+                for (i, extent) in extents.iter().enumerate() {
+                    put_entry(&mut block, i, extent);
+                }
+                self.cache.write_pointers(block_num, &block)
+            }
+            None => {
+                let inode = self.inode_map.get_mut(inode_num);
+                inode.block_ptrs = [MASTER_BLOCK_NUMBER; 8];
+                for (i, extent) in extents.iter().enumerate() {
+                    put_entry(&mut inode.block_ptrs, i, extent);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub (crate) fn alloc_extent(&mut self, inode_num: usize, offset: u64)
+                                -> device::Result<BlockNumber> {
+        if let Some(block_num) = self.lookup_extent(inode_num, offset)? {
+            return Ok(block_num)
+        }
+        if offset >= MAX_EXTENT_BLOCKS {
+            return Err(Error::Overflow)
+        }
+        // Every index node on the way down along with the entry that was followed
+        let mut path = vec![];
+        let mut node = None;
+        let mut level = self.inode_map.get(inode_num).level;
+        let mut leaf = self.read_node(inode_num, node)?;
+        while level > 0 {
+            let i = leaf.iter().rposition(|entry| entry.logical <= offset).unwrap_or(0);
+            let child = match leaf.get(i) {
+                Some(entry) => entry.start,
+                None => {
+                    let block = node.unwrap_or(MASTER_BLOCK_NUMBER);
+                    return Err(Error::Corrupt { block, kind: "extent node" })
+                }
+            };
+            path.push((node, leaf, i));
+            node = Some(child);
+            leaf = self.read_node(inode_num, node)?;
+            level -= 1;
+        }
+        let i = leaf.iter().position(|extent| extent.logical > offset).unwrap_or(leaf.len());
+        // Prefer the block right after the one holding the previous logical block
+        let block_num = match i.checked_sub(1).map(|previous| leaf[previous]) {
+            Some(previous) if previous.end() == offset => {
+                let hint = BlockNumber::new(previous.start.number + previous.length);
+                self.block_map.alloc_near(hint)?
+            }
+            _ => self.block_map.alloc()?
+        };
+        self.cache.write(block_num, vec![0; self.cache.device.config().block_size as usize])?;
+        let extent = Extent { logical: offset, start: block_num, length: 1 };
+        if i > 0 && joins(&leaf[i - 1], &extent) {
+            leaf[i - 1].length += 1;
+            if i < leaf.len() && joins(&leaf[i - 1], &leaf[i]) {
+                leaf[i - 1].length += leaf[i].length;
+                leaf.remove(i);
+            }
+        } else if i < leaf.len() && joins(&extent, &leaf[i]) {
+            leaf[i] = Extent { length: leaf[i].length + 1, .. extent };
+        } else {
+            leaf.insert(i, extent);
+        }
+        self.store_node(inode_num, path, node, leaf)?;
+        Ok(block_num)
+    }
+
+    // Writes the changed `extents` of `node` back, splitting every node on the `path` above it
+    // that overflows. The first logical block of a node may have changed so the index entries
+    // that lead to it are updated too.
+    fn store_node(&mut self, inode_num: usize, mut path: Path, mut node: Option<BlockNumber>,
+                  mut extents: Vec<Extent>) -> device::Result<()> {
+        let per_node = self.cache.device.block_numbers_per_block() / 2;
+        loop {
+            match node {
+                None if extents.len() > ROOT_ENTRIES => {
+                    let child = self.block_map.alloc()?;
+                    self.write_node(inode_num, None, &[index(extents[0].logical, child)])?;
+                    self.inode_map.get_mut(inode_num).level += 1;
+                    path.push((None, vec![index(extents[0].logical, child)], 0));
+                    node = Some(child);
+                }
+                Some(block_num) if extents.len() > per_node => {
+                    let right = extents.split_off(extents.len() / 2);
+                    let right_num = self.block_map.alloc()?;
+                    self.write_node(inode_num, Some(right_num), &right)?;
+                    self.write_node(inode_num, Some(block_num), &extents)?;
+                    let (parent, mut siblings, i) = match path.pop() {
+                        Some(parent) => parent,
+                        None => return Err(Error::Corrupt { block: block_num, kind: "extent node" })
+                    };
+                    siblings[i].logical = extents[0].logical;
+                    siblings.insert(i + 1, index(right[0].logical, right_num));
+                    node = parent;
+                    extents = siblings;
+                }
+                _ => break
+            }
+        }
+        self.write_node(inode_num, node, &extents)?;
+        let mut first = extents.first().map(|extent| extent.logical);
+        while let (Some(logical), Some((parent, mut siblings, i))) = (first, path.pop()) {
+            if siblings[i].logical == logical {
+                break
+            }
+            siblings[i].logical = logical;
+            self.write_node(inode_num, parent, &siblings)?;
+            first = if i == 0 { Some(logical) } else { None };
+        }
+        Ok(())
+    }
+
+    // The most metadata blocks that allocating one more block can dirty, the inode included.
+    // Every node on the path down is rewritten and split at worst, and the root may move down.
+    pub (crate) fn extent_alloc_cost(&self, inode_num: usize) -> usize {
+        2 * self.inode_map.get(inode_num).level as usize + 3
+    }
+
+    pub (crate) fn next_extent_block(&mut self, inode_num: usize, from: u64, mapped: bool)
+                                     -> device::Result<Option<u64>> {
+        let (extents, _) = self.read_extents(inode_num)?;
+        let mut position = from;
+        for extent in extents {
+            if extent.end() <= position {
+                continue
+            }
+            if mapped {
+                return Ok(Some(max(extent.logical, position)))
+            }
+            if extent.logical > position {
+                return Ok(Some(position))
+            }
+            position = extent.end();
+        }
+        Ok(if mapped { None } else { Some(position) })
+    }
+
+    pub (crate) fn allocated_extent_blocks(&mut self, inode_num: usize) -> device::Result<u64> {
+        let (extents, nodes) = self.read_extents(inode_num)?;
+        Ok(extents.iter().map(|extent| extent.length).sum::<u64>() + nodes.len() as u64)
+    }
+
+    // Frees every data block at or past the logical block `keep`. Only the nodes along the new
+    // end of the file are rewritten, and the tree shrinks while its root has a single child
+    // whose entries fit into the root.
+    pub (crate) fn truncate_extents(&mut self, inode_num: usize, keep: u64) -> device::Result<()> {
+        let mut level = self.inode_map.get(inode_num).level;
+        let mut root = self.read_node(inode_num, None)?;
+        self.truncate_node(inode_num, &mut root, level, keep)?;
+        while level > 0 && root.len() <= 1 {
+            let child = match root.first() {
+                Some(entry) => entry.start,
+                None => {
+                    level = 0;
+                    break
+                }
+            };
+            let children = self.read_node(inode_num, Some(child))?;
+            if children.len() > ROOT_ENTRIES {
+                break
+            }
+            self.cache.forget(child);
+            self.block_map.free(child);
+            root = children;
+            level -= 1;
+        }
+        self.write_node(inode_num, None, &root)?;
+        self.inode_map.get_mut(inode_num).level = level;
+        Ok(())
+    }
+
+    // Cuts the `entries` of a node at `level` back to the logical block `keep`. Children that
+    // are left without any entries are freed.
+    fn truncate_node(&mut self, inode_num: usize, entries: &mut Vec<Extent>, level: u8, keep: u64)
+                     -> device::Result<()> {
+        if level == 0 {
+            for extent in entries.iter_mut() {
+                let length = min(extent.length, keep.saturating_sub(extent.logical));
+                for block_num in extent.blocks().into_iter().skip(length as usize) {
+                    self.cache.forget(block_num);
+                    self.block_map.free(block_num);
+                }
+                extent.length = length;
+            }
+            entries.retain(|extent| extent.length > 0);
+            return Ok(())
+        }
+        let mut kept = vec![];
+        for (i, entry) in entries.iter().enumerate() {
+            let end = entries.get(i + 1).map_or(u64::MAX, |next| next.logical);
+            if end <= keep {
+                kept.push(*entry);
+                continue
+            }
+            let mut children = self.read_node(inode_num, Some(entry.start))?;
+            self.truncate_node(inode_num, &mut children, level - 1, keep)?;
+            if children.is_empty() {
+                self.cache.forget(entry.start);
+                self.block_map.free(entry.start);
+            } else {
+                self.write_node(inode_num, Some(entry.start), &children)?;
+                kept.push(*entry);
+            }
+        }
+        *entries = kept;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use block_number::{BlockOffset};
+    use device::{MemoryDevice};
+    use dir::{ROOT_INODE};
+    use fs::{INodeFormat};
+    use super::*;
+
+    fn fixture() -> (MemoryDevice, FileSystem) {
+        let disk = MemoryDevice::new(1024, Some(128)).unwrap();
+        let fs = FileSystem::new_with_format(disk.clone(), None, INodeFormat::Extents).unwrap();
+        (disk, fs)
+    }
+
+    #[test]
+    fn contiguous_file_is_one_extent() {
+        let (disk, mut fs) = fixture();
+        let file = fs.create(ROOT_INODE, "big").unwrap();
+        let data = (0 .. 300 * 128).map(|i| (i % 13) as u8).collect::<Vec<u8>>();
+        fs.write_at(file, 0, &data).unwrap();
+        let (extents, nodes) = fs.read_extents(file).unwrap();
+        assert_eq!(extents.len(), 1);
+        assert_eq!(extents[0].length, 300);
+        assert!(nodes.is_empty());
+        assert_eq!(fs.allocated_blocks(file).unwrap(), 300);
+        let mut out = vec![0; data.len()];
+        fs.read_at(file, 0, &mut out).unwrap();
+        assert_eq!(out, data);
+        fs.close().unwrap();
+        let mut fs = FileSystem::read(disk.clone()).unwrap().file_system;
+        assert_eq!(fs.master_block.format(), INodeFormat::Extents);
+        let mut out = vec![0; data.len()];
+        fs.read_at(file, 0, &mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn fragmented_file_grows_a_tree() {
+        let (_, mut fs) = fixture();
+        let file = fs.create(ROOT_INODE, "sparse").unwrap();
+        for i in (0 .. 40).rev() {
+            fs.write_at(file, i * 3 * 128, &[i as u8 + 1; 128]).unwrap();
+        }
+        let (extents, nodes) = fs.read_extents(file).unwrap();
+        assert_eq!(extents.len(), 40);
+        assert!(extents.windows(2).all(|pair| pair[0].logical < pair[1].logical));
+        // Every leaf split in half as the file grew at its front
+        assert_eq!(nodes.len(), 8 + 1);
+        assert_eq!(fs.inode_map.get(file).level, 2);
+        // Another fragment only rewrites the leaf it lands in
+        fs.write().unwrap();
+        fs.write_at(file, 200 * 128, &[7; 128]).unwrap();
+        assert_eq!(fs.cache.dirty_metadata(), 1);
+        fs.truncate(file, 40 * 3 * 128).unwrap();
+        for i in 0 .. 40 {
+            let mut out = [0; 1];
+            fs.read_at(file, i * 3 * 128 + 7, &mut out).unwrap();
+            assert_eq!(out[0], i as u8 + 1);
+        }
+        assert_eq!(fs.seek_data(file, 128).unwrap(), Some(3 * 128));
+        assert_eq!(fs.seek_hole(file, 3 * 128).unwrap(), Some(4 * 128));
+        fs.truncate(file, 9 * 128 + 1).unwrap();
+        let (extents, nodes) = fs.read_extents(file).unwrap();
+        assert_eq!(extents.len(), 4);
+        assert!(nodes.is_empty());
+        assert_eq!(fs.lookup_block_num_from_offset(file, BlockOffset::new(12)).unwrap(), None);
+        assert!(fs.fsck(false).unwrap().is_clean());
+        fs.remove_entry(ROOT_INODE, "sparse").unwrap();
+        fs.free_inode(file).unwrap();
+        assert!(fs.fsck(false).unwrap().is_clean());
+    }
+}
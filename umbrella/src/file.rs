@@ -0,0 +1,332 @@
+use std::cmp::{min, max};
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::time::{SystemTime};
+
+use block_number::{BlockNumber, BlockOffset};
+use cache::{SharedVec};
+use device::{self, Error};
+use dir::{self, DirEntry};
+use fs::{FileSystem, INodeFlags};
+
+// Reads and writes go a piece of at most this many blocks at a time so that a large buffer
+// does not pin all of its blocks in the cache at once.
+const PIECE_BLOCKS : u64 = 64;
+
+// Splits the `len` bytes at `offset` into pieces that never cross a multiple of `PIECE_BLOCKS`
+// blocks. Every piece is the file offset it starts at and its range of the buffer.
+pub (crate) fn pieces(offset: u64, len: usize, block_size: u64) -> Vec<(u64, Range<usize>)> {
+    let piece_len = PIECE_BLOCKS * block_size;
+    let mut pieces = vec![];
+    let mut done = 0;
+    while done < len {
+        let position = offset + done as u64;
+        let count = min(piece_len - position % piece_len, (len - done) as u64) as usize;
+        pieces.push((position, done .. done + count));
+        done += count;
+    }
+    pieces
+}
+
+// Which part of each block of a piece starting `start` bytes into its first block falls inside
+// of a buffer of `len` bytes, as the range of the block and where it goes in the buffer.
+fn spans(blocks: usize, start: usize, len: usize, block_size: usize) -> Vec<(Range<usize>, usize)> {
+    let mut done = 0;
+    (0 .. blocks).map(|i| {
+        let from = if i == 0 { start } else { 0 };
+        let count = min(block_size - from, len - done);
+        let span = (from .. from + count, done);
+        done += count;
+        span
+    }).collect()
+}
+
+// Copies the blocks of a piece into `buf`. Holes read as zeros.
+pub (crate) fn gather(blocks: &[Option<SharedVec<u8>>], start: usize, block_size: usize,
+                      buf: &mut [u8]) {
+    for (block, (range, at)) in blocks.iter().zip(spans(blocks.len(), start, buf.len(), block_size)) {
+        let dest = &mut buf[at .. at + range.len()];
+        match *block {
+            Some(ref block) => dest.copy_from_slice(&block.borrow()[range]),
+            None => {
+                for b in dest.iter_mut() {
+                    *b = 0;
+                }
+            }
+        }
+    }
+}
+
+// Copies `buf` into the blocks of a piece.
+pub (crate) fn scatter(blocks: &[(BlockNumber, SharedVec<u8>)], start: usize, block_size: usize,
+                       buf: &[u8]) {
+    for (block, (range, at)) in blocks.iter().zip(spans(blocks.len(), start, buf.len(), block_size)) {
+        let len = range.len();
+        block.1.borrow_mut()[range].copy_from_slice(&buf[at .. at + len]);
+    }
+}
+
+impl FileSystem {
+    // The cached blocks that hold the `len` bytes at `offset` of `inode_num`, `None` for holes.
+    pub (crate) fn data_blocks(&mut self, inode_num: usize, offset: u64, len: usize)
+                               -> device::Result<Vec<Option<SharedVec<u8>>>> {
+        let block_size = self.cache.device.config().block_size as u64;
+        let mut blocks = vec![];
+        for i in offset / block_size .. (offset + len as u64).div_ceil(block_size) {
+            let block = match self.lookup_block_num_from_offset(inode_num, BlockOffset::new(i))? {
+                Some(block_num) => Some(self.cache.read(block_num)?),
+                None => None
+            };
+            blocks.push(block);
+        }
+        Ok(blocks)
+    }
+
+    // Like `data_blocks` but the holes are allocated first and every block is marked dirty.
+    pub (crate) fn data_blocks_mut(&mut self, inode_num: usize, offset: u64, len: usize)
+                                   -> device::Result<Vec<(BlockNumber, SharedVec<u8>)>> {
+        let block_size = self.cache.device.config().block_size as u64;
+        // The inode that `wrote` stamps afterwards
+        self.make_room(1)?;
+        let mut block_nums = vec![];
+        for i in offset / block_size .. (offset + len as u64).div_ceil(block_size) {
+            let offset = BlockOffset::new(i);
+            let block_num = match self.lookup_block_num_from_offset(inode_num, offset)? {
+                Some(block_num) => block_num,
+                None => {
+                    let cost = self.alloc_cost(inode_num, offset);
+                    self.make_room(cost)?;
+                    self.alloc_block_num_from_offset(inode_num, offset)?
+                }
+            };
+            block_nums.push(block_num);
+        }
+        // A commit marks every cached block clean so the handles are only taken once nothing
+        // can commit anymore
+        let mut blocks = vec![];
+        for block_num in block_nums {
+            blocks.push((block_num, self.cache.read_mut(block_num)?));
+        }
+        Ok(blocks)
+    }
+
+    // Grows `inode_num` to at least `end` bytes and stamps it as modified after a write.
+    pub (crate) fn wrote(&mut self, inode_num: usize, end: u64) {
+        let inode = self.inode_map.get_mut(inode_num);
+        if inode.length < end {
+            inode.length = end;
+        }
+        inode.mdate = SystemTime::now();
+    }
+
+    /// Reads bytes starting at `offset` of `inode_num` into `buf`. Reading stops at the end of
+    /// the file so the number of bytes actually read is returned. Unallocated blocks read as zeros.
+    pub fn read_at(&mut self, inode_num: usize, offset: u64, buf: &mut [u8]) -> device::Result<usize> {
+        let block_size = self.cache.device.config().block_size as u64;
+        let length = self.inode_map.get(inode_num).length;
+        if offset >= length {
+            return Ok(0)
+        }
+        let total = min(buf.len() as u64, length - offset) as usize;
+        for (position, range) in pieces(offset, total, block_size) {
+            let blocks = self.data_blocks(inode_num, position, range.len())?;
+            gather(&blocks, (position % block_size) as usize, block_size as usize, &mut buf[range]);
+        }
+        Ok(total)
+    }
+
+    /// Writes all of `buf` starting at `offset` of `inode_num`, allocating blocks as needed.
+    /// The file grows when the write ends past its current length.
+    pub fn write_at(&mut self, inode_num: usize, offset: u64, buf: &[u8]) -> device::Result<usize> {
+        let block_size = self.cache.device.config().block_size as u64;
+        for (position, range) in pieces(offset, buf.len(), block_size) {
+            let blocks = self.data_blocks_mut(inode_num, position, range.len())?;
+            scatter(&blocks, (position % block_size) as usize, block_size as usize, &buf[range]);
+        }
+        self.wrote(inode_num, offset + buf.len() as u64);
+        Ok(buf.len())
+    }
+
+    /// Returns the first offset at or after `offset` that holds data, or `None` if there is no
+    /// data between `offset` and the end of the file.
+    pub fn seek_data(&mut self, inode_num: usize, offset: u64) -> device::Result<Option<u64>> {
+        self.seek(inode_num, offset, true)
+    }
+
+    /// Returns the first offset at or after `offset` that lies in a hole. The end of the file
+    /// counts as a hole so this is only `None` when `offset` is past the end of the file.
+    pub fn seek_hole(&mut self, inode_num: usize, offset: u64) -> device::Result<Option<u64>> {
+        self.seek(inode_num, offset, false)
+    }
+
+    fn seek(&mut self, inode_num: usize, offset: u64, data: bool) -> device::Result<Option<u64>> {
+        let block_size = self.cache.device.config().block_size as u64;
+        let length = self.inode_map.get(inode_num).length;
+        if offset >= length {
+            return Ok(None)
+        }
+        let found = self.next_block(inode_num, offset / block_size, data)?
+            .map(|block| max(block * block_size, offset));
+        match found {
+            Some(found) if found < length => Ok(Some(found)),
+            _ if data => Ok(None),
+            _ => Ok(Some(length))
+        }
+    }
+
+    /// Creates an empty file `name` inside of `parent` and returns its inode number.
+    /// If `name` already names a file that file is emptied instead.
+    pub fn create(&mut self, parent: usize, name: &str) -> device::Result<usize> {
+        if ! self.is_dir(parent) {
+            return Err(Error::NotADirectory(format!("inode {}", parent)))
+        }
+        match self.lookup(parent, name)? {
+            Some(file) if self.is_dir(file) => Err(Error::IsADirectory(name.to_string())),
+            Some(file) => {
+                self.truncate(file, 0)?;
+                Ok(file)
+            }
+            None => {
+                dir::validate_name(name)?;
+                self.make_room(2)?;
+                let file = match self.inode_map.alloc(INodeFlags::FILE) {
+                    Some(file) => file,
+                    None => return Err(Error::Size("out of inodes".to_string()))
+                };
+                if let Err(err) = self.add_entry(parent, &DirEntry::new(file, name)) {
+                    self.free_inode(file)?;
+                    return Err(err)
+                }
+                Ok(file)
+            }
+        }
+    }
+
+    /// Appends everything `reader` produces to the end of `inode_num`.
+    pub fn copy_in<R: Read>(&mut self, inode_num: usize, reader: &mut R) -> device::Result<u64> {
+        let mut buf = vec![0; self.cache.device.config().block_size as usize];
+        let mut offset = self.inode_map.get(inode_num).length;
+        let start = offset;
+        loop {
+            let read = reader.read(&mut buf)?;
+            if read == 0 {
+                return Ok(offset - start)
+            }
+            offset += self.write_at(inode_num, offset, &buf[.. read])? as u64;
+        }
+    }
+
+    /// Writes the whole contents of `inode_num` to `writer`.
+    pub fn copy_out<W: Write>(&mut self, inode_num: usize, writer: &mut W) -> device::Result<u64> {
+        let mut buf = vec![0; self.cache.device.config().block_size as usize];
+        let mut offset = 0;
+        loop {
+            let read = self.read_at(inode_num, offset, &mut buf)?;
+            if read == 0 {
+                return Ok(offset)
+            }
+            writer.write_all(&buf[.. read])?;
+            offset += read as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use device::MemoryDevice;
+    use dir::{ROOT_INODE};
+    use super::*;
+
+    #[test]
+    fn write_read_across_blocks() {
+        let device = MemoryDevice::new(1024, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
+        let inode_num = fs.inode_map.alloc(INodeFlags::FILE).unwrap();
+        let data = (0 .. 5000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        assert_eq!(fs.write_at(inode_num, 0, &data).unwrap(), data.len());
+        assert_eq!(fs.inode_map.get(inode_num).length, data.len() as u64);
+        let mut out = vec![0; data.len()];
+        assert_eq!(fs.read_at(inode_num, 0, &mut out).unwrap(), data.len());
+        assert_eq!(out, data);
+        let mut middle = vec![0; 300];
+        assert_eq!(fs.read_at(inode_num, 100, &mut middle).unwrap(), 300);
+        assert_eq!(&middle[..], &data[100 .. 400]);
+    }
+
+    #[test]
+    fn overwrite_and_read_past_end() {
+        let device = MemoryDevice::new(128, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
+        let inode_num = fs.inode_map.alloc(INodeFlags::FILE).unwrap();
+        fs.write_at(inode_num, 0, b"hello world").unwrap();
+        fs.write_at(inode_num, 6, b"umbrella").unwrap();
+        assert_eq!(fs.inode_map.get(inode_num).length, 14);
+        let mut out = vec![0; 32];
+        let read = fs.read_at(inode_num, 0, &mut out).unwrap();
+        assert_eq!(&out[.. read], b"hello umbrella");
+        assert_eq!(fs.read_at(inode_num, 14, &mut out).unwrap(), 0);
+    }
+
+    #[test]
+    fn create_copy_in_out() {
+        let device = MemoryDevice::new(256, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
+        let data = (0 .. 1000).map(|i| (i % 7) as u8).collect::<Vec<u8>>();
+        let file = fs.create(ROOT_INODE, "data").unwrap();
+        assert_eq!(fs.copy_in(file, &mut &data[..]).unwrap(), data.len() as u64);
+        let mut out = vec![];
+        assert_eq!(fs.copy_out(file, &mut out).unwrap(), data.len() as u64);
+        assert_eq!(out, data);
+        assert_eq!(fs.create(ROOT_INODE, "data").unwrap(), file);
+        assert_eq!(fs.inode_map.get(file).length, 0);
+        assert_eq!(fs.lookup_block_num_from_offset(file, BlockOffset::zero()).unwrap(), None);
+        fs.mkdir(ROOT_INODE, "dir").unwrap();
+        assert!(fs.create(ROOT_INODE, "dir").is_err());
+    }
+
+    #[test]
+    fn failed_create_frees_its_inode() {
+        let device = MemoryDevice::new(128, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
+        let file = fs.create(ROOT_INODE, "a").unwrap();
+        match fs.create(ROOT_INODE, "a_name_longer_than_a_directory_slot") {
+            Err(Error::InvalidName(_)) => {}
+            res => panic!("expected InvalidName but got {:?}", res)
+        }
+        assert_eq!(fs.inode_map.get(file + 1).flags, INodeFlags::FREE);
+        fs.create(ROOT_INODE, "b").unwrap();
+        // The root directory needs a second block for the next entry
+        while fs.block_map.alloc().is_ok() {}
+        match fs.create(ROOT_INODE, "nowhere") {
+            Err(Error::Size(_)) => {}
+            res => panic!("expected Size but got {:?}", res)
+        }
+        assert_eq!(fs.inode_map.get(file + 2).flags, INodeFlags::FREE);
+        assert_eq!(fs.lookup(ROOT_INODE, "nowhere").unwrap(), None);
+    }
+
+    #[test]
+    fn sparse_holes() {
+        let device = MemoryDevice::new(1024, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
+        let file = fs.create(ROOT_INODE, "sparse").unwrap();
+        fs.write_at(file, 10 * 128 + 3, b"data").unwrap();
+        assert_eq!(fs.inode_map.get(file).length, 10 * 128 + 7);
+        assert_eq!(fs.allocated_blocks(file).unwrap(), 2);
+        let mut hole = vec![1; 10 * 128];
+        assert_eq!(fs.read_at(file, 0, &mut hole).unwrap(), hole.len());
+        assert!(hole.iter().all(|b| *b == 0));
+        assert_eq!(fs.allocated_blocks(file).unwrap(), 2);
+        assert_eq!(fs.seek_data(file, 0).unwrap(), Some(10 * 128));
+        assert_eq!(fs.seek_data(file, 10 * 128 + 5).unwrap(), Some(10 * 128 + 5));
+        assert_eq!(fs.seek_hole(file, 0).unwrap(), Some(0));
+        assert_eq!(fs.seek_hole(file, 10 * 128).unwrap(), Some(10 * 128 + 7));
+        assert_eq!(fs.seek_hole(file, 10 * 128 + 7).unwrap(), None);
+        fs.truncate(file, 1 << 30).unwrap();
+        assert_eq!(fs.read_at(file, (1 << 30) - 128, &mut hole[.. 128]).unwrap(), 128);
+        assert!(hole.iter().all(|b| *b == 0));
+        assert_eq!(fs.seek_data(file, 11 * 128).unwrap(), None);
+        assert_eq!(fs.seek_hole(file, 11 * 128).unwrap(), Some(11 * 128));
+        assert_eq!(fs.allocated_blocks(file).unwrap(), 2);
+    }
+}
@@ -1,52 +1,276 @@
+use std::cmp::{min};
 use std::fmt::{self, Display, Formatter};
-use std::time::{SystemTime};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::mem;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use bit_vec::BitVec;
-use bincode::{serialize_into, deserialize_from};
 
 use block_number::{BlockNumber, BlockOffset, MASTER_BLOCK_NUMBER, Step, Sequence};
-use device::{self, BlockDevice, Error};
-use cache::{SharedVec, Cache};
+use device::{self, BlockDevice, FileDevice, RawDevice, Error};
+use checksum::{self, CHECKSUM_LEN};
+use encoding::{get_u16, get_u32, get_u64, put_u16, put_u32, put_u64};
+use cache::{Cache, Stats};
+use journal::{Journal, Transaction};
 
 bitflags! {
-    #[derive(Serialize, Deserialize)]
     pub struct MasterBlockFlags: u8 {
         const SYNCED = 0b10000000;
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Identifies a block device that holds an umbrella file system.
+pub const MAGIC : &[u8; 4] = b"UMBR";
+/// The version of the on-disk format. Images written with any other version are refused.
+pub const FORMAT_VERSION : u16 = 5;
+
+// The master block is stored at the start of block zero as:
+//
+//   | magic: "UMBR" | version: u16 | flags: u8 | inode_format: u8 | block_size: u16 |
+//   | inode_count: u16 | reserved: u32 | block_count: u64 | block_map: u64 | inode_map: u64 |
+//   | journal: u64 | journal_blocks: u64 | checksum: u32 |
+//
+// Every integer is little endian and the rest of the block is zero. The checksum is the CRC32C of
+// everything before it. Block map blocks and inode table slots end with a checksum as well.
+const MASTER_BLOCK_LEN : usize = 56 + CHECKSUM_LEN;
+
+/// How the inodes of a file system map logical blocks to device blocks.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum INodeFormat {
+    /// A uniformly deep tree of block pointers
+    Tree,
+    /// A tree of extents that each map a run of contiguous blocks, see `extent`
+    Extents
+}
+
+#[derive(Clone, Debug)]
 pub struct MasterBlock {
     block_size:  u16,
     block_count: u64,
     inode_count: u16,
     block_map:   BlockNumber,
     inode_map:   BlockNumber,
+    journal:     BlockNumber,
+    journal_blocks: u64,
+    inode_format: INodeFormat,
     pub flags:   MasterBlockFlags,
 }
 
+fn block_map_blocks(block_size: u16, block_count: u64) -> u64 {
+    let bits_per_block = (block_size as usize - CHECKSUM_LEN) as u64 * 8;
+    block_count.div_ceil(bits_per_block)
+}
+
+fn inode_blocks(block_size: u16, inode_count: u16) -> u64 {
+    let inodes_per_block = (block_size as usize / INODE_SIZE) as u64;
+    (inode_count as u64).div_ceil(inodes_per_block)
+}
+
 impl MasterBlock {
     pub fn new(block_size: u16, block_count: u64, inode_count: u16) -> MasterBlock {
+        let inode_map = 1 + block_map_blocks(block_size, block_count);
         MasterBlock {
             block_size,
             block_count,
             inode_count,
             block_map: BlockNumber::new(1),
-            inode_map: BlockNumber::new(2 + (block_count / block_size as u64) / 8),
+            inode_map: BlockNumber::new(inode_map),
+            journal:   BlockNumber::new(inode_map + inode_blocks(block_size, inode_count)),
+            journal_blocks: (block_count / 16).clamp(8, 1024),
+            inode_format: INodeFormat::Tree,
             flags:     MasterBlockFlags::SYNCED
         }
     }
 
+    /// The layout this file system gets on a device of `block_count` blocks. The block map grows
+    /// or shrinks with the device and the inode table and the journal move to follow it.
+    pub (crate) fn resized(&self, block_count: u64) -> MasterBlock {
+        let inode_map = 1 + block_map_blocks(self.block_size, block_count);
+        MasterBlock {
+            block_count,
+            inode_map: BlockNumber::new(inode_map),
+            journal:   BlockNumber::new(inode_map + self.inode_blocks()),
+            ..self.clone()
+        }
+    }
+
+    pub fn journal(&self) -> Journal {
+        Journal::new(self.journal, self.journal_blocks)
+    }
+
     pub fn block_map_blocks(&self) -> u64 {
-        1 + self.block_count / self.block_size as u64 / 8
+        block_map_blocks(self.block_size, self.block_count)
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    pub fn inode_count(&self) -> u16 {
+        self.inode_count
+    }
+
+    pub fn format(&self) -> INodeFormat {
+        self.inode_format
+    }
+
+    pub fn inodes_per_block(&self) -> usize {
+        self.block_size as usize / INODE_SIZE
+    }
+
+    /// How many blocks the inode table takes up.
+    pub fn inode_blocks(&self) -> u64 {
+        inode_blocks(self.block_size, self.inode_count)
+    }
+
+    /// The first block after the master block, the block map, the inode table, and the journal.
+    pub fn data_start(&self) -> BlockNumber {
+        BlockNumber::new(self.journal.number + self.journal_blocks)
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        buf[0 .. 4].copy_from_slice(MAGIC);
+        put_u16(buf, 4, FORMAT_VERSION);
+        buf[6] = self.flags.bits();
+        buf[7] = match self.inode_format {
+            INodeFormat::Tree    => 0,
+            INodeFormat::Extents => 1
+        };
+        put_u16(buf, 8, self.block_size);
+        put_u16(buf, 10, self.inode_count);
+        put_u32(buf, 12, 0);
+        put_u64(buf, 16, self.block_count);
+        put_u64(buf, 24, self.block_map.number);
+        put_u64(buf, 32, self.inode_map.number);
+        put_u64(buf, 40, self.journal.number);
+        put_u64(buf, 48, self.journal_blocks);
+        checksum::seal(&mut buf[.. MASTER_BLOCK_LEN]);
+    }
+
+    pub fn decode(buf: &[u8]) -> device::Result<MasterBlock> {
+        if &buf[0 .. 4] != MAGIC {
+            return Err(Error::Format("missing the umbrella magic number".to_string()))
+        }
+        let version = get_u16(buf, 4);
+        if version != FORMAT_VERSION {
+            let err_msg = format!(
+                "format version [{}] is not supported, expected [{}]",
+                version,
+                FORMAT_VERSION
+            );
+            return Err(Error::Format(err_msg))
+        }
+        if ! checksum::verify(&buf[.. MASTER_BLOCK_LEN]) {
+            return Err(Error::Corrupt { block: MASTER_BLOCK_NUMBER, kind: "master block" })
+        }
+        let block_size = get_u16(buf, 8);
+        if (block_size as usize) < INODE_SIZE || ! (block_size as usize).is_multiple_of(INODE_SIZE) {
+            let err_msg = format!(
+                "block_size [{}] is not a positive multiple of {}",
+                block_size,
+                INODE_SIZE
+            );
+            return Err(Error::Format(err_msg))
+        }
+        let inode_format = match buf[7] {
+            0 => INodeFormat::Tree,
+            1 => INodeFormat::Extents,
+            format => return Err(Error::Format(format!("unknown inode format [{}]", format)))
+        };
+        Ok(MasterBlock {
+            block_size,
+            block_count: get_u64(buf, 16),
+            inode_count: get_u16(buf, 10),
+            block_map:   BlockNumber::new(get_u64(buf, 24)),
+            inode_map:   BlockNumber::new(get_u64(buf, 32)),
+            journal:     BlockNumber::new(get_u64(buf, 40)),
+            journal_blocks: get_u64(buf, 48),
+            inode_format,
+            flags:       MasterBlockFlags::from_bits_truncate(buf[6]),
+        })
     }
 
-    pub fn write(&self, device: &mut BlockDevice) -> device::Result<()> {
+    // Makes sure the regions the master block describes are in order and fit on `device`.
+    fn check_geometry(&self, device: &dyn BlockDevice) -> device::Result<()> {
+        let in_order = self.block_size == device.config().block_size
+            && self.block_count <= device.config().block_count
+            && self.inode_map.number == 1 + self.block_map_blocks()
+            && self.journal.number == self.inode_map.number + self.inode_blocks()
+            && self.data_start().number <= self.block_count;
+        if in_order {
+            Ok(())
+        } else {
+            Err(Error::Corrupt { block: MASTER_BLOCK_NUMBER, kind: "master block" })
+        }
+    }
+
+    /// Where copies of the master block may live on a device of `block_count` blocks. The
+    /// locations only depend on the size of the device so they can still be found when the
+    /// master block itself is unreadable.
+    pub fn backup_locations(block_count: u64) -> [BlockNumber; 2] {
+        [BlockNumber::new(block_count / 2), BlockNumber::new(block_count - 1)]
+    }
+
+    /// The backups this file system keeps. Locations that fall inside of the metadata region
+    /// are skipped.
+    pub fn backups(&self) -> Vec<BlockNumber> {
+        let data_start = self.data_start();
+        let mut backups = MasterBlock::backup_locations(self.block_count)
+            .iter()
+            .cloned()
+            .filter(|block_num| data_start <= *block_num)
+            .collect::<Vec<_>>();
+        backups.dedup();
+        backups
+    }
+
+    /// Whether `block_num` belongs to the file system itself rather than to some inode.
+    pub fn is_reserved(&self, block_num: BlockNumber) -> bool {
+        block_num < self.data_start() || self.backups().contains(&block_num)
+    }
+
+    /// Reads the master block of `device`. When the master block does not validate every backup
+    /// location is tried in turn and the location of the backup that was used is returned.
+    pub fn read(device: &dyn BlockDevice) -> device::Result<(MasterBlock, Option<BlockNumber>)> {
+        fn read_at(device: &dyn BlockDevice, block_num: BlockNumber) -> device::Result<MasterBlock> {
+            let mut mb_vec = vec![0; device.config().block_size as usize];
+            device.read(block_num, &mut mb_vec)?;
+            let master_block = MasterBlock::decode(&mb_vec)?;
+            master_block.check_geometry(device)?;
+            Ok(master_block)
+        }
+        let err = match read_at(device, MASTER_BLOCK_NUMBER) {
+            Ok(master_block) => return Ok((master_block, None)),
+            Err(err) => err
+        };
+        for backup in MasterBlock::backup_locations(device.config().block_count).iter() {
+            if let Ok(master_block) = read_at(device, *backup) {
+                if master_block.backups().contains(backup) {
+                    return Ok((master_block, Some(*backup)))
+                }
+            }
+        }
+        Err(err)
+    }
+
+    pub fn write(&self, device: &mut dyn BlockDevice) -> device::Result<()> {
         let mut mb_vec = vec![0; self.block_size as usize];
-        serialize_into(&mut mb_vec[..], &self)?;
+        self.encode(&mut mb_vec);
         device.write(MASTER_BLOCK_NUMBER, &mut mb_vec[..])
     }
 
-    pub fn write_sync_status(&mut self, device: &mut BlockDevice, status: bool) -> device::Result<()> {
+    /// Copies the master block to every backup location.
+    pub fn write_backups(&self, device: &mut dyn BlockDevice) -> device::Result<()> {
+        let mut mb_vec = vec![0; self.block_size as usize];
+        self.encode(&mut mb_vec);
+        for backup in self.backups() {
+            device.write(backup, &mut mb_vec)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_sync_status(&mut self, device: &mut dyn BlockDevice, status: bool) -> device::Result<()> {
         let mut master_block = self.clone();
         master_block.flags.set(MasterBlockFlags::SYNCED, status);
         master_block.write(device)?;
@@ -55,33 +279,225 @@ impl MasterBlock {
     }
 }
 
+/// Opens the device image at `path` whatever it is named. The block size comes from the master
+/// block, or from one of its backups when block zero is damaged. Images that neither can describe
+/// fall back to the block size in a `<name>.<block_size>.dev` file name.
+pub fn open_image<P: AsRef<Path>>(path: P) -> device::Result<Box<dyn BlockDevice>> {
+    let path = path.as_ref();
+    if let Some(block_size) = probe_block_size(path)? {
+        return Ok(Box::new(RawDevice::open(path, block_size)?))
+    }
+    match FileDevice::open(&path.to_string_lossy()) {
+        Ok(device) => Ok(Box::new(device)),
+        Err(_) => Err(Error::Format(format!("{}: cannot tell the block size", path.display())))
+    }
+}
+
+// Backups are only looked for with power of two block sizes from 128 up.
+fn probe_block_size(path: &Path) -> device::Result<Option<u16>> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut read_at = |offset: u64| -> Option<MasterBlock> {
+        let mut buf = [0; MASTER_BLOCK_LEN];
+        file.seek(SeekFrom::Start(offset)).ok()?;
+        file.read_exact(&mut buf).ok()?;
+        MasterBlock::decode(&buf).ok()
+    };
+    if let Some(master_block) = read_at(0) {
+        return Ok(Some(master_block.block_size))
+    }
+    for shift in 7 .. 16 {
+        let block_size = 1u64 << shift;
+        let block_count = file_len / block_size;
+        if block_count == 0 {
+            break
+        }
+        for backup in MasterBlock::backup_locations(block_count).iter() {
+            match read_at(backup.number * block_size) {
+                Some(ref master_block) if master_block.block_size as u64 == block_size => {
+                    return Ok(Some(master_block.block_size))
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(None)
+}
+
 // Theoretically this should be a doubly linked free list as the asymptotics on all the operations
 // I want to support would be optimal. I choose a bitvec even though its asymptotics are worse. I
 // did this because bitvecs have much lower constants on all the operations in question.
+// To keep allocation cheap the search is next-fit: it resumes where the last one stopped and it
+// skips every group of `GROUP_BITS` blocks that has no free blocks left. Inside of a group the
+// bitvec is scanned a whole word at a time.
 pub struct BlockMap {
     vec: BitVec,
+    // How many blocks of each group are free
+    free: Vec<usize>,
+    // Which spans of `DIRTY_BITS` bits changed since the map was last written
+    dirty: BitVec,
+    // Blocks that may not be allocated but that are written out as free, see `hold`
+    held: Vec<BlockNumber>,
+    cursor: usize
 }
 
+const GROUP_BITS : usize = 4096;
+const DIRTY_BITS : usize = 64;
+
 impl BlockMap {
+    /// A map with every block free. All of it counts as dirty since none of it is on disk yet.
     pub fn new(block_count: u64) -> BlockMap {
-        BlockMap {
-            vec: BitVec::from_elem(block_count as usize, false)
+        let mut block_map = BlockMap::from_bit_vec(BitVec::from_elem(block_count as usize, false));
+        block_map.mark_dirty();
+        block_map
+    }
+
+    fn from_bit_vec(vec: BitVec) -> BlockMap {
+        let mut free = vec![0; vec.len().div_ceil(GROUP_BITS)];
+        for (i, b) in vec.iter().enumerate() {
+            if ! b {
+                free[i / GROUP_BITS] += 1;
+            }
         }
+        let dirty = BitVec::from_elem(vec.len().div_ceil(DIRTY_BITS), false);
+        BlockMap { vec, free, dirty, held: vec![], cursor: 0 }
     }
 
     pub fn set(&mut self, block_number: BlockNumber, b: bool) {
-        self.vec.set(block_number.index(), b)
+        let i = block_number.index();
+        if self.vec[i] != b {
+            if b {
+                self.free[i / GROUP_BITS] -= 1;
+            } else {
+                self.free[i / GROUP_BITS] += 1;
+            }
+            self.vec.set(i, b);
+            self.dirty.set(i / DIRTY_BITS, true)
+        }
+    }
+
+    /// Whether any of the bits for the blocks `from .. to` changed since the last `clean`.
+    pub fn is_dirty(&self, from: usize, to: usize) -> bool {
+        let to = min(to, self.vec.len());
+        from < to && (from / DIRTY_BITS .. (to - 1) / DIRTY_BITS + 1).any(|i| self.dirty[i])
+    }
+
+    pub (crate) fn clean(&mut self) {
+        self.dirty.clear()
+    }
+
+    /// Marks the whole map dirty so that the next write stores every block of it.
+    pub (crate) fn mark_dirty(&mut self) {
+        self.dirty.set_all()
     }
 
-    fn find_free(vec: &BitVec) -> Option<usize> {
-        vec.iter().enumerate().find(|&(_, b)| b == false).map(|(i, _)| i)
+    /// Keeps `block_number` from being allocated while it is still written out as free, or as
+    /// no longer allocated when it is. Resizing holds the blocks it is clearing out this way so
+    /// that the old layout stays consistent until the new one replaces it.
+    pub (crate) fn hold(&mut self, block_number: BlockNumber) {
+        self.set(block_number, true);
+        self.dirty.set(block_number.index() / DIRTY_BITS, true);
+        self.held.push(block_number);
+    }
+
+    /// Frees every block that is held.
+    pub (crate) fn release(&mut self) {
+        for block_number in mem::take(&mut self.held) {
+            self.set(block_number, false);
+        }
+    }
+
+    // The map as it is written out.
+    fn to_bytes(&self) -> Vec<u8> {
+        if self.held.is_empty() {
+            return self.vec.to_bytes()
+        }
+        let mut vec = self.vec.clone();
+        for block_number in &self.held {
+            vec.set(block_number.index(), false);
+        }
+        vec.to_bytes()
+    }
+
+    /// Grows the map with free blocks or drops every block at or past `block_count`.
+    pub (crate) fn resize(&mut self, block_count: u64) {
+        let mut vec = self.vec.clone();
+        let len = vec.len();
+        if (block_count as usize) < len {
+            vec.truncate(block_count as usize);
+        } else {
+            vec.grow(block_count as usize - len, false);
+        }
+        let cursor = self.cursor;
+        *self = BlockMap::from_bit_vec(vec);
+        self.cursor = cursor;
+        self.mark_dirty();
+    }
+
+    pub fn free_blocks(&self) -> u64 {
+        self.free.iter().sum::<usize>() as u64
+    }
+
+    pub fn is_allocated(&self, block_number: BlockNumber) -> bool {
+        self.vec.get(block_number.index()).unwrap_or(false)
+    }
+
+    // Finds the first free block in `from .. to` which must lie inside of a single group.
+    fn scan(&self, from: usize, to: usize) -> Option<usize> {
+        let storage = self.vec.storage();
+        let mut i = from;
+        while i < to {
+            let word = ! storage[i / 32] >> (i % 32);
+            if word == 0 {
+                i = (i / 32 + 1) * 32;
+                continue
+            }
+            let found = i + word.trailing_zeros() as usize;
+            return if found < to { Some(found) } else { None }
+        }
+        None
+    }
+
+    // Finds the first free block at or after `start`, wrapping around to the beginning.
+    fn find_free(&self, start: usize) -> Option<usize> {
+        let len = self.vec.len();
+        if len == 0 {
+            return None
+        }
+        let start = start % len;
+        let groups = self.free.len();
+        let first_group = start / GROUP_BITS;
+        for n in 0 .. groups + 1 {
+            let group = (first_group + n) % groups;
+            if self.free[group] == 0 {
+                continue
+            }
+            let from = if n == 0 { start } else { group * GROUP_BITS };
+            let to = if n == groups { start } else { min((group + 1) * GROUP_BITS, len) };
+            if let Some(i) = self.scan(from, to) {
+                return Some(i)
+            }
+        }
+        None
     }
 
     pub fn alloc(&mut self) -> device::Result<BlockNumber> {
-        match BlockMap::find_free(&self.vec) {
+        let cursor = self.cursor;
+        self.alloc_from(cursor)
+    }
+
+    /// Allocates the first free block at or after `hint`, so that `hint` itself is taken
+    /// whenever it is free.
+    pub fn alloc_near(&mut self, hint: BlockNumber) -> device::Result<BlockNumber> {
+        self.alloc_from(hint.index())
+    }
+
+    fn alloc_from(&mut self, start: usize) -> device::Result<BlockNumber> {
+        match self.find_free(start) {
             Some(i) => {
                 let block_number = BlockNumber::new(i as u64);
                 self.set(block_number, true);
+                self.cursor = i + 1;
                 Ok(block_number)
             }
             None => {
@@ -90,6 +506,41 @@ impl BlockMap {
         }
     }
 
+    /// Allocates `n` consecutive blocks and returns the first of them.
+    pub fn alloc_run(&mut self, n: u64) -> device::Result<BlockNumber> {
+        let len = self.vec.len();
+        let n = n as usize;
+        let mut start = if len == 0 { 0 } else { self.cursor % len };
+        // How far the search has gone past the starting point
+        let mut searched = 0;
+        while n > 0 && searched < len {
+            let first = match self.find_free(start) {
+                Some(first) => first,
+                None => break
+            };
+            searched += (first + len - start) % len;
+            if first + n > len {
+                searched += len - first;
+                start = 0;
+                continue
+            }
+            match (first .. first + n).find(|i| self.vec[*i]) {
+                Some(used) => {
+                    searched += used + 1 - first;
+                    start = (used + 1) % len;
+                }
+                None => {
+                    for i in first .. first + n {
+                        self.set(BlockNumber::new(i as u64), true);
+                    }
+                    self.cursor = first + n;
+                    return Ok(BlockNumber::new(first as u64))
+                }
+            }
+        }
+        Err(Error::Size(format!("no run of {} free blocks", n)))
+    }
+
     pub fn free(&mut self, block_number: BlockNumber) {
         self.set(block_number, false)
     }
@@ -134,7 +585,6 @@ impl Display for BlockMap {
 
 
 bitflags! {
-    #[derive(Serialize, Deserialize)]
     pub struct INodeFlags: u8 {
         const FREE = 0b1000_0000;
         const FILE = 0b0100_0000;
@@ -165,22 +615,32 @@ impl INodeFlags {
 }
 
 bitflags! {
-    #[derive(Serialize, Deserialize)]
     struct Permissions: u16 {
         const UNUSED = 0b0000_0000_0000_0000;
     }
 }
-// I use rusts SystemTime to represent and serialize time. This type cannot be fit into
-// 32 bits but that restriction is silly and wrong. See the 2038 unix-time apocalypse for details.
-#[derive(Debug, Serialize, Deserialize)]
+/// Every inode gets a slot of this many bytes in the inode table. A block holds as many slots
+/// as fit inside of it.
+pub const INODE_SIZE : usize = 128;
+
+// I use rusts SystemTime to represent time. This type cannot be fit into 32 bits but that
+// restriction is silly and wrong. See the 2038 unix-time apocalypse for details.
+// An inode is stored in its slot of the inode table as:
+//
+//   | cdate: u64 | cdate_nanos: u32 | mdate: u64 | mdate_nanos: u32 | flags: u8 | level: u8 |
+//   | perms: u16 | length: u64 | block_ptrs: [u64; 8] | reserved | checksum: u32 |
+//
+// Every integer is little endian and dates count from the unix epoch. The reserved bytes are zero
+// and the checksum in the last four bytes of the slot covers everything before it.
+#[derive(Debug, PartialEq)]
 pub struct INode {
-    cdate:      SystemTime,
-    mdate:      SystemTime,
-    flags:      INodeFlags,
-    perms:      Permissions,
-    length:     u64,
-    level:      u8,
-    block_ptrs: [BlockNumber; 8]
+                cdate:      SystemTime,
+    pub (crate) mdate:      SystemTime,
+    pub (crate) flags:      INodeFlags,
+                perms:      Permissions,
+    pub (crate) length:     u64,
+    pub (crate) level:      u8,
+    pub (crate) block_ptrs: [BlockNumber; 8]
 }
 
 impl INode {
@@ -195,17 +655,83 @@ impl INode {
             block_ptrs: [BlockNumber::new(0); 8]
         }
     }
+
+    pub fn flags(&self) -> INodeFlags {
+        self.flags
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn encode(&self, slot: &mut [u8]) {
+        for b in slot.iter_mut() {
+            *b = 0;
+        }
+        encode_time(slot, 0, self.cdate);
+        encode_time(slot, 12, self.mdate);
+        slot[24] = self.flags.bits();
+        slot[25] = self.level;
+        put_u16(slot, 26, self.perms.bits());
+        put_u64(slot, 28, self.length);
+        for (i, block_ptr) in self.block_ptrs.iter().enumerate() {
+            put_u64(slot, 36 + i * 8, block_ptr.number);
+        }
+        checksum::seal(slot);
+    }
+
+    pub fn decode(slot: &[u8]) -> device::Result<INode> {
+        let mut block_ptrs = [BlockNumber::new(0); 8];
+        for (i, block_ptr) in block_ptrs.iter_mut().enumerate() {
+            *block_ptr = BlockNumber::new(get_u64(slot, 36 + i * 8));
+        }
+        Ok(INode {
+            cdate: decode_time(slot, 0)?,
+            mdate: decode_time(slot, 12)?,
+            flags: INodeFlags::from_bits_truncate(slot[24]),
+            perms: Permissions::from_bits_truncate(get_u16(slot, 26)),
+            length: get_u64(slot, 28),
+            level: slot[25],
+            block_ptrs
+        })
+    }
+}
+
+// Times before the unix epoch are stored as the epoch itself.
+fn encode_time(buf: &mut [u8], at: usize, time: SystemTime) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    put_u64(buf, at, since_epoch.as_secs());
+    put_u32(buf, at + 8, since_epoch.subsec_nanos());
+}
+
+fn decode_time(buf: &[u8], at: usize) -> device::Result<SystemTime> {
+    let (secs, nanos) = (get_u64(buf, at), get_u32(buf, at + 8));
+    if nanos >= 1_000_000_000 {
+        return Err(Error::Format(format!("[{}] nanoseconds do not make up a time", nanos)))
+    }
+    UNIX_EPOCH.checked_add(Duration::new(secs, nanos))
+        .ok_or_else(|| Error::Format(format!("[{}] seconds past the epoch is out of range", secs)))
 }
 
 pub struct INodeMap {
-    vec: Vec<INode>
+    vec: Vec<INode>,
+    // Which inodes changed since the table was last written and how many of them did
+    dirty: BitVec,
+    dirty_count: usize
 }
 
 impl INodeMap {
     pub fn new(inode_count: u16) -> INodeMap {
         let now = SystemTime::now();
         let nodes = (0..inode_count).map(|_| INode::new(now)).collect::<Vec<_>>();
-        INodeMap { vec: nodes }
+        let mut inode_map = INodeMap::from_inodes(nodes);
+        inode_map.mark_dirty();
+        inode_map
+    }
+
+    fn from_inodes(vec: Vec<INode>) -> INodeMap {
+        let dirty = BitVec::from_elem(vec.len(), false);
+        INodeMap { vec, dirty, dirty_count: 0 }
     }
 
     fn find_free(&self) -> Option<usize> {
@@ -216,11 +742,19 @@ impl INodeMap {
             .map(|(i,_)| i)
     }
 
+    fn set_dirty(&mut self, index: usize) {
+        if ! self.dirty[index] {
+            self.dirty.set(index, true);
+            self.dirty_count += 1;
+        }
+    }
+
     pub fn alloc(&mut self, flags: INodeFlags) -> Option<usize> {
         self.find_free().map(move |i| {
+            self.set_dirty(i);
             let inode = &mut self.vec[i];
+            *inode = INode::new(SystemTime::now());
             inode.flags = flags;
-            inode.mdate = SystemTime::now();
             i
         })
     }
@@ -229,13 +763,36 @@ impl INodeMap {
         &self.vec[index]
     }
 
+    /// Hands out the inode for changing, which marks it dirty.
     pub fn get_mut(&mut self, index: usize) -> &mut INode {
+        self.set_dirty(index);
         &mut self.vec[index]
     }
 
     pub fn free(&mut self, block_number: BlockNumber) {
+        self.set_dirty(block_number.index());
         self.vec[block_number.index()].flags = INodeFlags::FREE
     }
+
+    pub fn is_dirty(&self, index: usize) -> bool {
+        self.dirty.get(index).unwrap_or(false)
+    }
+
+    /// How many inodes changed since the table was last written.
+    pub fn dirty_count(&self) -> usize {
+        self.dirty_count
+    }
+
+    pub (crate) fn clean(&mut self) {
+        self.dirty.clear();
+        self.dirty_count = 0
+    }
+
+    /// Marks every inode dirty so that the next write stores the whole table.
+    pub (crate) fn mark_dirty(&mut self) {
+        self.dirty.set_all();
+        self.dirty_count = self.vec.len()
+    }
 }
 
 impl Display for INodeMap {
@@ -275,97 +832,219 @@ pub struct FileSystem {
     pub master_block: MasterBlock,
     pub block_map:    BlockMap,
     pub inode_map:    INodeMap,
-        cache:        Cache
+    pub (crate) cache: Cache
 }
 
 pub struct Mount {
     pub file_system: FileSystem,
-    pub clean_mount: bool
+    pub clean_mount: bool,
+    /// How many blocks were recovered from the journal
+    pub replayed:    usize,
+    /// The backup the master block was recovered from when the master block was damaged
+    pub backup:      Option<BlockNumber>
 }
 
 impl FileSystem {
-    pub fn new(device: BlockDevice) -> FileSystem {
-        const INODE_COUNT : u16 = 50;
-        let block_size = device.config.block_size;
-        let block_count = device.config.block_count;
+    /// Lays out a fresh file system on `device`. Without an explicit `inode_count` there is
+    /// one inode for every eight blocks but never fewer than fifty.
+    pub fn new<D: BlockDevice + 'static>(device: D, inode_count: Option<u16>)
+                                          -> device::Result<FileSystem> {
+        FileSystem::new_with_format(device, inode_count, INodeFormat::Tree)
+    }
+
+    pub fn new_with_format<D: BlockDevice + 'static>(mut device: D, inode_count: Option<u16>,
+                                                     format: INodeFormat)
+                                                     -> device::Result<FileSystem> {
+        let block_size = device.config().block_size;
+        let block_count = device.config().block_count;
+        if (block_size as usize) < INODE_SIZE {
+            let err_msg = format!("newfs: block_size [{}] is less than {}", block_size, INODE_SIZE);
+            return Err(Error::Size(err_msg))
+        }
+        let inode_count = inode_count
+            .unwrap_or_else(|| (block_count / 8).clamp(50, u16::MAX as u64) as u16);
         let mut block_map = BlockMap::new(block_count);
-        let master_block = MasterBlock::new(block_size, block_count, INODE_COUNT);
-        let claimed_blocks = master_block.block_map_blocks() + INODE_COUNT as u64;
-        for i in Sequence::new(MASTER_BLOCK_NUMBER, claimed_blocks) {
+        let mut master_block = MasterBlock::new(block_size, block_count, inode_count);
+        master_block.inode_format = format;
+        if master_block.data_start().number >= block_count {
+            let err_msg = format!(
+                "newfs: block_count [{}] is too small to hold [{}] inodes",
+                block_count,
+                inode_count
+            );
+            return Err(Error::Size(err_msg))
+        }
+        for i in Sequence::new(MASTER_BLOCK_NUMBER, master_block.data_start().number) {
             block_map.set(i, true);
         }
-        let inode_map = INodeMap::new(INODE_COUNT);
+        for backup in master_block.backups() {
+            block_map.set(backup, true);
+        }
+        master_block.write_backups(&mut device)?;
+        let inode_map = INodeMap::new(inode_count);
         let cache = Cache::new(device);
-        FileSystem { master_block, block_map, inode_map, cache }
+        let mut file_system = FileSystem { master_block, block_map, inode_map, cache };
+        // The whole inode table is dirty and would not fit into the journal
+        file_system.write_unjournaled()?;
+        file_system.make_root()?;
+        Ok(file_system)
     }
 
-    pub fn write(&mut self) -> device::Result<()> {
+    // Collects the dirty parts of the block map and the inode table along with every dirty
+    // pointer block.
+    pub (crate) fn transaction(&self) -> device::Result<Transaction> {
         let master_block = &self.master_block;
-        let mut bm_vec = vec![0u8; master_block.block_size as usize];
+        let block_size = master_block.block_size as usize;
+        let mut transaction = Transaction::new();
         let mut block_number = master_block.block_map;
-        let mut i = 0;
-        for b in self.block_map.vec.to_bytes() {
-            bm_vec[i] = b;
-            i += 1;
-            if i == master_block.block_size as usize {
-                self.cache.device.write(block_number, &mut bm_vec)?;
-                block_number.inc();
-                i = 0;
+        let bits_per_block = (block_size - CHECKSUM_LEN) * 8;
+        let bytes = self.block_map.to_bytes();
+        for (i, chunk) in bytes.chunks(block_size - CHECKSUM_LEN).enumerate() {
+            if self.block_map.is_dirty(i * bits_per_block, (i + 1) * bits_per_block) {
+                let mut bm_vec = vec![0u8; block_size];
+                bm_vec[.. chunk.len()].copy_from_slice(chunk);
+                checksum::seal(&mut bm_vec);
+                transaction.write(block_number, bm_vec);
             }
+            block_number.inc();
         }
-        if i != 0 {
-            for j in i .. master_block.block_size as usize {
-                bm_vec[j] = 0;
-            }
-            self.cache.device.write(block_number, &mut bm_vec)?;
+        if master_block.inode_map < block_number {
+            return Err(Error::Size("the block map overlaps the inode table".to_string()))
         }
-        assert!(block_number < master_block.inode_map);
         block_number = master_block.inode_map;
-        for node in self.inode_map.vec.iter() {
-            let mut node_bytes = vec![0u8; master_block.block_size as usize];
-            serialize_into(&mut node_bytes[..], &node)?;
-            self.cache.device.write(block_number, &mut node_bytes)?;
+        let per_block = master_block.inodes_per_block();
+        for (i, nodes) in self.inode_map.vec.chunks(per_block).enumerate() {
+            if (i * per_block .. i * per_block + nodes.len()).any(|n| self.inode_map.is_dirty(n)) {
+                let mut node_bytes = vec![0u8; block_size];
+                for (slot, node) in node_bytes.chunks_mut(INODE_SIZE).zip(nodes) {
+                    node.encode(slot);
+                }
+                transaction.write(block_number, node_bytes);
+            }
             block_number.inc();
         }
-        self.cache.write_all()?;
+        self.cache.journal_pointers(&mut transaction);
+        Ok(transaction)
+    }
+
+    /// Writes every dirty data block in the cache and then commits the dirty parts of the block
+    /// map and the inode table along with every dirty pointer block as a single journaled
+    /// transaction. The file system stays mounted so this is also how `sync` flushes it.
+    pub fn write(&mut self) -> device::Result<()> {
+        let transaction = self.transaction()?;
+        // Data goes out before the metadata that points at it
+        self.cache.write_data()?;
+        self.cache.device.sync()?;
+        self.master_block.journal().commit(&mut self.cache.device, transaction)?;
+        self.block_map.clean();
+        self.inode_map.clean();
+        self.cache.committed()
+    }
+
+    // Like `write` but puts everything straight on the device. This is for metadata that is
+    // laid out anew as a whole and could never fit into the journal.
+    pub (crate) fn write_unjournaled(&mut self) -> device::Result<()> {
+        let transaction = self.transaction()?;
+        self.cache.write_data()?;
+        transaction.apply(&mut *self.cache.device)?;
+        self.cache.device.sync()?;
+        self.block_map.clean();
+        self.inode_map.clean();
+        self.cache.committed()
+    }
+
+    /// Commits early when the metadata that is dirty now and `blocks` more blocks of it might
+    /// not fit into a single journal transaction. Callers that are about to dirty metadata call
+    /// this first so that `write` never sees a transaction the journal cannot hold. Each of the
+    /// `blocks` may also come with a block allocated or freed in another part of the block map.
+    pub (crate) fn make_room(&mut self, blocks: usize) -> device::Result<()> {
+        self.reserve(blocks, blocks)
+    }
+
+    // Like `make_room` for changes that dirty up to `block_map` block map blocks on top of
+    // `blocks` other metadata blocks.
+    fn reserve(&mut self, blocks: usize, block_map: usize) -> device::Result<()> {
+        let inode_blocks = self.master_block.inode_blocks() as usize;
+        let block_map_blocks = self.master_block.block_map_blocks() as usize;
+        let pending = min(self.dirty_block_map_blocks() + block_map, block_map_blocks)
+            + min(self.inode_map.dirty_count(), inode_blocks)
+            + self.cache.dirty_metadata();
+        if pending + blocks > self.master_block.journal().capacity(self.master_block.block_size) {
+            self.write()?;
+        }
         Ok(())
     }
 
-    pub fn read(mut device: BlockDevice) -> device::Result<Mount> {
-        let mut mb_vec = vec![0; device.config.block_size as usize];
-        device.read(MASTER_BLOCK_NUMBER, &mut mb_vec)?;
-        let mut master_block : MasterBlock = deserialize_from(&mb_vec[..])?;
+    // How many blocks of the block map the next transaction has to write.
+    fn dirty_block_map_blocks(&self) -> usize {
+        let bits_per_block = (self.master_block.block_size as usize - CHECKSUM_LEN) * 8;
+        (0 .. self.master_block.block_map_blocks() as usize)
+            .filter(|i| self.block_map.is_dirty(i * bits_per_block, (i + 1) * bits_per_block))
+            .count()
+    }
+
+    /// How much I/O the file system did since it was mounted and how well the cache did.
+    pub fn stats(&self) -> Stats {
+        self.cache.stats()
+    }
+
+    /// Limits how many blocks the cache holds before it starts evicting.
+    pub fn set_cache_capacity(&mut self, blocks: usize) -> device::Result<()> {
+        self.cache.set_capacity(blocks)
+    }
+
+    pub fn read<D: BlockDevice + 'static>(mut device: D) -> device::Result<Mount> {
+        let (master_block, backup) = MasterBlock::read(&device)?;
+        // A backup is only as fresh as the last close so the journal is always replayed
+        let clean_mount = backup.is_none() && master_block.flags.contains(MasterBlockFlags::SYNCED);
+        let replayed = if clean_mount {
+            0
+        } else {
+            master_block.journal().replay(&mut device)?
+        };
+        // The replayed transaction may have been a resize that switched to a new layout
+        let mut master_block = if replayed > 0 { MasterBlock::read(&device)?.0 } else { master_block };
+        let block_size = master_block.block_size as usize;
         let mut bit_vec = BitVec::new();
         let mut block_number = master_block.block_map;
-        for _ in 1 .. master_block.block_map_blocks() + 1 {
-            let mut bm_vec = vec![0; master_block.block_size as usize];
-            device.read(block_number, &mut bm_vec)?;
+        let mut bm_bytes = vec![0; master_block.block_map_blocks() as usize * block_size];
+        device.read_blocks(block_number, master_block.block_map_blocks(), &mut bm_bytes)?;
+        for bm_vec in bm_bytes.chunks(block_size) {
+            if ! checksum::verify(bm_vec) {
+                return Err(Error::Corrupt { block: block_number, kind: "block map" })
+            }
             block_number.inc();
-            bit_vec.extend(BitVec::from_bytes(&bm_vec));
+            bit_vec.extend(BitVec::from_bytes(&bm_vec[.. bm_vec.len() - CHECKSUM_LEN]));
         }
         bit_vec.truncate(master_block.block_count as usize);
-        let block_map = BlockMap { vec: bit_vec };
-        assert!(block_number <= master_block.inode_map);
+        let block_map = BlockMap::from_bit_vec(bit_vec);
         let mut nodes = vec![];
         let mut block_number = master_block.inode_map;
-        for _ in 0 .. master_block.inode_count {
-            let mut node_bytes = vec![0u8; master_block.block_size as usize];
-            device.read(block_number, &mut node_bytes)?;
+        let mut table = vec![0u8; master_block.inode_blocks() as usize * block_size];
+        device.read_blocks(block_number, master_block.inode_blocks(), &mut table)?;
+        for node_bytes in table.chunks(block_size) {
+            for slot in node_bytes.chunks(INODE_SIZE).take(master_block.inodes_per_block()) {
+                if nodes.len() == master_block.inode_count as usize {
+                    break
+                }
+                if ! checksum::verify(slot) {
+                    return Err(Error::Corrupt { block: block_number, kind: "inode table" })
+                }
+                nodes.push(INode::decode(slot)?);
+            }
             block_number.inc();
-            let node = deserialize_from(&node_bytes[..])?;
-            nodes.push(node);
         }
-        let inode_map = INodeMap { vec: nodes };
-        let clean_mount = master_block.flags.contains(MasterBlockFlags::SYNCED);
+        let inode_map = INodeMap::from_inodes(nodes);
         master_block.write_sync_status(&mut device, false)?;
         let cache = Cache::new(device);
         let file_system = FileSystem { master_block, block_map, inode_map, cache };
-        Ok(Mount { file_system, clean_mount })
+        Ok(Mount { file_system, clean_mount, replayed, backup })
     }
 
     pub fn close(mut self) -> device::Result<()> {
         self.write()?;
-        self.master_block.write_sync_status(&mut self.cache.device, true)
+        self.master_block.write_sync_status(&mut self.cache.device, true)?;
+        self.master_block.write_backups(&mut self.cache.device)
     }
 
     /// This is the static version of getDiskAddr where allocp = false.
@@ -374,13 +1053,15 @@ impl FileSystem {
     pub fn lookup_block_num_from_offset<'a>(&mut self, inode_num: usize, offset: BlockOffset) ->
         device::Result<Option<BlockNumber>>
     {
+        if self.master_block.inode_format == INodeFormat::Extents {
+            return self.lookup_extent(inode_num, offset.index() as u64)
+        }
         fn rec(cache: &mut Cache,
                offset: BlockOffset,
-               vec: SharedVec<BlockNumber>,
+               block_ptrs: &[BlockNumber],
                level: u8) ->
             device::Result<Option<BlockNumber>>
         {
-            let block_ptrs = vec.borrow();
             if level == 0 {
                 if offset < block_ptrs.len() {
                     let block_num = block_ptrs[offset.index()];
@@ -400,36 +1081,123 @@ impl FileSystem {
                 if next_block_index == MASTER_BLOCK_NUMBER {
                     Ok(None)
                 } else {
-                    let next_block_ptrs = cache.read_pointers(next_block_index)?;
-                    rec(cache, next_offset, next_block_ptrs, level - 1)
+                    let next_block_ptrs = cache.read_pointers(next_block_index)?.to_vec();
+                    rec(cache, next_offset, &next_block_ptrs, level - 1)
+                }
+            }
+        }
+        let inode = self.inode_map.get(inode_num);
+        let bnpl = self.cache.device.block_numbers_per_level(inode.level);
+        if offset >= inode.block_ptrs.len() * bnpl {
+            // Everything past what the tree can currently address is a hole
+            return Ok(None)
+        }
+        rec(&mut self.cache, offset, &inode.block_ptrs, inode.level)
+    }
+
+    /// Finds the first logical block at or after `from` that is allocated (when `mapped` is
+    /// true) or that is a hole (when `mapped` is false). Subtrees that are entirely holes are
+    /// skipped without being read. Everything past the end of the tree is a hole.
+    pub (crate) fn next_block(&mut self, inode_num: usize, from: u64, mapped: bool) ->
+        device::Result<Option<u64>>
+    {
+        if self.master_block.inode_format == INodeFormat::Extents {
+            return self.next_extent_block(inode_num, from, mapped)
+        }
+        fn rec(cache: &mut Cache,
+               block_ptrs: &[BlockNumber],
+               level: u8,
+               base: u64,
+               from: u64,
+               mapped: bool) ->
+            device::Result<Option<u64>>
+        {
+            let span = cache.device.block_numbers_per_level(level) as u64;
+            for (i, block_ptr) in block_ptrs.iter().enumerate() {
+                let start = base + i as u64 * span;
+                if start + span <= from {
+                    continue
+                }
+                let first = if start < from { from } else { start };
+                if *block_ptr == MASTER_BLOCK_NUMBER {
+                    if ! mapped {
+                        return Ok(Some(first))
+                    }
+                } else if level == 0 {
+                    if mapped {
+                        return Ok(Some(first))
+                    }
+                } else {
+                    let children = cache.read_pointers(*block_ptr)?.to_vec();
+                    if let Some(found) = rec(cache, &children, level - 1, start, from, mapped)? {
+                        return Ok(Some(found))
+                    }
                 }
             }
+            Ok(None)
         }
         let inode = self.inode_map.get(inode_num);
-        let block_ptrs = SharedVec::new(inode.block_ptrs.iter().map(|n| *n).collect());
-        rec(&mut self.cache, offset, block_ptrs, inode.level)
+        let found = rec(&mut self.cache, &inode.block_ptrs, inode.level, 0, from, mapped)?;
+        if found.is_none() && ! mapped {
+            let capacity = (inode.block_ptrs.len() *
+                            self.cache.device.block_numbers_per_level(inode.level)) as u64;
+            return Ok(Some(if from < capacity { capacity } else { from }))
+        }
+        Ok(found)
+    }
+
+    /// Counts the data and pointer blocks that `inode_num` holds on the device.
+    pub fn allocated_blocks(&mut self, inode_num: usize) -> device::Result<u64> {
+        if self.master_block.inode_format == INodeFormat::Extents {
+            return self.allocated_extent_blocks(inode_num)
+        }
+        fn rec(cache: &mut Cache, block_ptrs: &[BlockNumber], level: u8) -> device::Result<u64> {
+            let mut count = 0;
+            for block_ptr in block_ptrs.iter() {
+                if *block_ptr == MASTER_BLOCK_NUMBER {
+                    continue
+                }
+                count += 1;
+                if level > 0 {
+                    let children = cache.read_pointers(*block_ptr)?.to_vec();
+                    count += rec(cache, &children, level - 1)?;
+                }
+            }
+            Ok(count)
+        }
+        let inode = self.inode_map.get(inode_num);
+        rec(&mut self.cache, &inode.block_ptrs, inode.level)
     }
 
     /// This is the allocing version of getDiskAddr where allocp = true.
     /// This function operates on a file system and takes an inode num instead of
     /// operating on inode and taking a file system. This satiates the borrow checker.
+    /// A new data block is placed right after the block holding the previous offset when
+    /// possible so that sequential writes stay contiguous on the device.
     pub fn alloc_block_num_from_offset(&mut self, inode_num: usize, offset: BlockOffset) ->
         device::Result<BlockNumber>
     {
+        if self.master_block.inode_format == INodeFormat::Extents {
+            return self.alloc_extent(inode_num, offset.index() as u64)
+        }
         fn rec(block_map: &mut BlockMap,
                cache: &mut Cache,
                offset: BlockOffset,
-               vec: SharedVec<BlockNumber>,
-               level: u8) ->
+               block_ptrs: &mut [BlockNumber],
+               level: u8,
+               hint: Option<BlockNumber>) ->
             device::Result<BlockNumber>
         {
-            let mut block_ptrs = vec.borrow_mut();
             if level == 0 {
                 if offset < block_ptrs.len() {
                     let block_num = block_ptrs[offset.index()];
                     if block_num == MASTER_BLOCK_NUMBER {
-                        let new_block_num = block_map.alloc()?;
+                        let new_block_num = match hint {
+                            Some(hint) => block_map.alloc_near(hint)?,
+                            None => block_map.alloc()?
+                        };
                         block_ptrs[offset.index()] = new_block_num;
+                        cache.write(new_block_num, vec![0; cache.device.config().block_size as usize])?;
                         Ok(new_block_num)
                     } else {
                         Ok(block_num)
@@ -447,15 +1215,29 @@ impl FileSystem {
                     block_ptrs[next_block.index()] = new_block_num;
                     let new_block =
                         vec![MASTER_BLOCK_NUMBER; cache.device.block_numbers_per_block()];
-                    cache.write_pointers(new_block_num, new_block);
+                    cache.write_pointers(new_block_num, &new_block)?;
                     new_block_num
                 } else {
                     next_block_index
                 };
-                let next_block_ptrs = cache.read_pointers(next_block_num)?;
-                rec(block_map, cache, next_offset, next_block_ptrs, level - 1)
+                // The handle keeps the block cached while the subtree below it changes
+                let node = cache.read_pointers_mut(next_block_num)?;
+                let mut next_block_ptrs = node.to_vec();
+                let res = rec(block_map, cache, next_offset, &mut next_block_ptrs, level - 1, hint);
+                node.copy_from_slice(&next_block_ptrs);
+                res
             }
         }
+        if let Some(block_num) = self.lookup_block_num_from_offset(inode_num, offset)? {
+            return Ok(block_num)
+        }
+        let hint = if offset == 0 {
+            None
+        } else {
+            let previous = BlockOffset::new(offset.index() as u64 - 1);
+            self.lookup_block_num_from_offset(inode_num, previous)?
+                .map(|block_num| BlockNumber::new(block_num.number + 1))
+        };
         let inode = self.inode_map.get_mut(inode_num);
         let mut bnpl = self.cache.device.block_numbers_per_level(inode.level);
         while offset >= inode.block_ptrs.len() * bnpl {
@@ -466,39 +1248,232 @@ impl FileSystem {
                 new_block[i] = *block_num;
                 i += 1;
             }
-            self.cache.write_pointers(new_block_num, new_block);
+            self.cache.write_pointers(new_block_num, &new_block)?;
             let mut new_block_ptrs = [MASTER_BLOCK_NUMBER; 8];
             new_block_ptrs[0] = new_block_num;
             inode.block_ptrs = new_block_ptrs;
             inode.level += 1;
             bnpl = self.cache.device.block_numbers_per_level(inode.level);
         }
-        let block_ptrs = SharedVec::new(inode.block_ptrs.iter().map(|n| *n).collect());
-        let res = rec(&mut self.block_map, &mut self.cache, offset, block_ptrs.clone(), inode.level);
-        for (i, n) in block_ptrs.vec.borrow().iter().enumerate() {
-            inode.block_ptrs[i] = *n
+        rec(&mut self.block_map, &mut self.cache, offset, &mut inode.block_ptrs, inode.level, hint)
+    }
+
+    // The most metadata blocks that allocating the block at `offset` of `inode_num` can dirty,
+    // the inode included. Every level the tree grows by adds a pointer block and every level
+    // of the path down to the new block may need a new pointer block or change an old one.
+    pub (crate) fn alloc_cost(&self, inode_num: usize, offset: BlockOffset) -> usize {
+        if self.master_block.inode_format == INodeFormat::Extents {
+            return self.extent_alloc_cost(inode_num)
+        }
+        let inode = self.inode_map.get(inode_num);
+        let mut level = inode.level;
+        while offset >= inode.block_ptrs.len() * self.cache.device.block_numbers_per_level(level) {
+            level += 1;
+        }
+        2 * level as usize - inode.level as usize + 1
+    }
+
+    /// Sets the length of `inode_num` to `length` bytes. When the file shrinks every data and
+    /// pointer block that only held bytes past the new end is returned to the block map and
+    /// the tree is collapsed to the smallest level that still fits. Growing leaves a hole.
+    pub fn truncate(&mut self, inode_num: usize, length: u64) -> device::Result<()> {
+        fn free_tree(block_map: &mut BlockMap,
+                     cache: &mut Cache,
+                     block_num: BlockNumber,
+                     level: u8) ->
+            device::Result<()>
+        {
+            if level > 0 {
+                let children = cache.read_pointers(block_num)?.to_vec();
+                for child in children {
+                    if child != MASTER_BLOCK_NUMBER {
+                        free_tree(block_map, cache, child, level - 1)?;
+                    }
+                }
+            }
+            cache.forget(block_num);
+            block_map.free(block_num);
+            Ok(())
+        }
+        // `base` is the logical block that `block_ptrs[0]` starts at. Every block at or past
+        // the logical block `keep` is freed.
+        fn rec(block_map: &mut BlockMap,
+               cache: &mut Cache,
+               block_ptrs: &mut [BlockNumber],
+               level: u8,
+               base: u64,
+               keep: u64) ->
+            device::Result<()>
+        {
+            let span = cache.device.block_numbers_per_level(level) as u64;
+            for (i, block_ptr) in block_ptrs.iter_mut().enumerate() {
+                let start = base + i as u64 * span;
+                if *block_ptr == MASTER_BLOCK_NUMBER || start + span <= keep {
+                    continue
+                }
+                let empty = if start >= keep {
+                    true
+                } else if level > 0 {
+                    let node = cache.read_pointers_mut(*block_ptr)?;
+                    let mut children = node.to_vec();
+                    rec(block_map, cache, &mut children, level - 1, start, keep)?;
+                    node.copy_from_slice(&children);
+                    children.iter().all(|child| *child == MASTER_BLOCK_NUMBER)
+                } else {
+                    false
+                };
+                if empty {
+                    free_tree(block_map, cache, *block_ptr, level)?;
+                    *block_ptr = MASTER_BLOCK_NUMBER;
+                }
+            }
+            Ok(())
+        }
+        // Cutting the tree back never changes more than growing it to its first block would,
+        // but the blocks it frees can be anywhere in the block map
+        let cost = self.alloc_cost(inode_num, BlockOffset::new(0));
+        let block_map_blocks = self.master_block.block_map_blocks() as usize;
+        self.reserve(cost, block_map_blocks)?;
+        let block_size = self.cache.device.config().block_size as u64;
+        let old_length = self.inode_map.get(inode_num).length;
+        if length < old_length && ! length.is_multiple_of(block_size) {
+            // Whatever used to be past the end must read as zeros if the file grows again
+            let last = BlockOffset::new(length / block_size);
+            if let Some(block_num) = self.lookup_block_num_from_offset(inode_num, last)? {
+                let block = self.cache.read_mut(block_num)?;
+                for b in block.borrow_mut()[(length % block_size) as usize ..].iter_mut() {
+                    *b = 0;
+                }
+            }
+        }
+        let keep = length.div_ceil(block_size);
+        if self.master_block.inode_format == INodeFormat::Extents {
+            self.truncate_extents(inode_num, keep)?;
+        } else {
+            let FileSystem { ref mut block_map, ref mut inode_map, ref mut cache, .. } = *self;
+            let inode = inode_map.get_mut(inode_num);
+            let mut block_ptrs = inode.block_ptrs;
+            let width = block_ptrs.len();
+            rec(block_map, cache, &mut block_ptrs, inode.level, 0, keep)?;
+            while inode.level > 0 && block_ptrs[1 ..].iter().all(|n| *n == MASTER_BLOCK_NUMBER) {
+                if block_ptrs[0] == MASTER_BLOCK_NUMBER {
+                    inode.level = 0;
+                    break
+                }
+                let children = cache.read_pointers(block_ptrs[0])?.to_vec();
+                if children[width ..].iter().any(|n| *n != MASTER_BLOCK_NUMBER) {
+                    break
+                }
+                cache.forget(block_ptrs[0]);
+                block_map.free(block_ptrs[0]);
+                block_ptrs.copy_from_slice(&children[.. width]);
+                inode.level -= 1;
+            }
+            inode.block_ptrs = block_ptrs;
         }
-        res
+        let inode = self.inode_map.get_mut(inode_num);
+        inode.length = length;
+        inode.mdate = SystemTime::now();
+        Ok(())
+    }
+
+    /// Returns every block of `inode_num` to the block map and then frees the inode itself.
+    pub fn free_inode(&mut self, inode_num: usize) -> device::Result<()> {
+        self.truncate(inode_num, 0)?;
+        self.inode_map.free(BlockNumber::new(inode_num as u64));
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{env, fs, process};
+    use device::{MemoryDevice};
+    use dir::{ROOT_INODE};
     use super::*;
-    use bincode::*;
 
     #[test]
-    fn inode_seralize_len() {
-        let now = SystemTime::now();
-        let inode = INode::new(now);
-        let v = serialize(&inode).unwrap();
-        assert!(v.len() < 128)
+    fn inode_encoding_round_trip() {
+        let mut inode = INode::new(SystemTime::now());
+        inode.flags = INodeFlags::FILE;
+        inode.length = 0x0102_0304_0506;
+        inode.level = 2;
+        inode.block_ptrs[7] = BlockNumber::new(u64::MAX);
+        let mut slot = [0xff; INODE_SIZE];
+        inode.encode(&mut slot);
+        assert!(slot[100 .. INODE_SIZE - CHECKSUM_LEN].iter().all(|b| *b == 0));
+        assert!(checksum::verify(&slot));
+        assert_eq!(INode::decode(&slot).unwrap(), inode);
+        // Times that no `SystemTime` can hold are rejected instead of panicking
+        put_u32(&mut slot, 8, 1_000_000_000);
+        assert!(INode::decode(&slot).is_err());
+        put_u32(&mut slot, 8, 0);
+        put_u64(&mut slot, 12, u64::MAX);
+        assert!(INode::decode(&slot).is_err());
+    }
+
+    #[test]
+    fn master_block_rejects_other_versions() {
+        let master_block = MasterBlock::new(512, 4096, 300);
+        let mut buf = vec![0; 512];
+        master_block.encode(&mut buf);
+        assert_eq!(&buf[0 .. 4], MAGIC);
+        let decoded = MasterBlock::decode(&buf).unwrap();
+        assert_eq!(decoded.data_start(), master_block.data_start());
+        assert_eq!(decoded.inode_count(), 300);
+        buf[4] = FORMAT_VERSION as u8 + 1;
+        match MasterBlock::decode(&buf) {
+            Err(Error::Format(_)) => {}
+            res => panic!("expected Format but got {:?}", res)
+        }
+        assert!(MasterBlock::decode(&[0; 512]).is_err());
+        buf[4] = FORMAT_VERSION as u8;
+        for block_size in [0, 64, 200] {
+            let mut bad = buf.clone();
+            put_u16(&mut bad, 8, block_size);
+            checksum::seal(&mut bad[.. MASTER_BLOCK_LEN]);
+            match MasterBlock::decode(&bad) {
+                Err(Error::Format(_)) => {}
+                res => panic!("expected Format but got {:?}", res)
+            }
+        }
+        buf[20] ^= 1;
+        match MasterBlock::decode(&buf) {
+            Err(Error::Corrupt { block, .. }) => assert_eq!(block, MASTER_BLOCK_NUMBER),
+            res => panic!("expected Corrupt but got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn mount_detects_corruption() {
+        let disk = MemoryDevice::new(256, Some(128)).unwrap();
+        FileSystem::new(disk.clone(), None).unwrap().close().unwrap();
+        let mut device = disk.clone();
+        let mut block = vec![0; 128];
+        device.read(BlockNumber::new(3), &mut block).unwrap();
+        block[40] ^= 0x80;
+        device.write(BlockNumber::new(3), &mut block).unwrap();
+        match FileSystem::read(device) {
+            Err(Error::Corrupt { block, kind }) => {
+                assert_eq!(block, BlockNumber::new(3));
+                assert_eq!(kind, "inode table");
+            }
+            Err(err) => panic!("expected Corrupt but got {}", err),
+            Ok(_) => panic!("mounted a corrupt image")
+        }
+        let device = MemoryDevice::new(256, Some(128)).unwrap();
+        assert_eq!(MasterBlock::backup_locations(256)[0], BlockNumber::new(128));
+        match FileSystem::read(device) {
+            Err(Error::Format(_)) => {}
+            Err(err) => panic!("expected Format but got {}", err),
+            Ok(_) => panic!("mounted a zeroed image")
+        }
     }
 
     #[test]
     fn inode_alloc_read_simple() {
-        let device = BlockDevice::create("foo", 128, Some(128)).unwrap();
-        let mut fs = FileSystem::new(device);
+        let device = MemoryDevice::new(128, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
         let zero   = BlockOffset::new(0);
         let inode_num = fs.inode_map.alloc(INodeFlags::FILE).unwrap();
         let alloced_block_num = fs.alloc_block_num_from_offset(inode_num, zero).unwrap();
@@ -508,8 +1483,8 @@ mod tests {
 
     #[test]
     fn inode_alloc_read_many() {
-        let device = BlockDevice::create("foo", 1024, Some(128)).unwrap();
-        let mut fs = FileSystem::new(device);
+        let device = MemoryDevice::new(1024, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
         let inode_num = fs.inode_map.alloc(INodeFlags::FILE).unwrap();
         let seq = Sequence::new(BlockOffset::zero(), 200);
         println!();
@@ -526,8 +1501,8 @@ mod tests {
 
     #[test]
     fn inode_alloc_read_middle() {
-        let device = BlockDevice::create("foo", 1024, Some(128)).unwrap();
-        let mut fs = FileSystem::new(device);
+        let device = MemoryDevice::new(1024, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
         let inode_num = fs.inode_map.alloc(INodeFlags::FILE).unwrap();
         let far = BlockOffset::new(300);
         let alloced_block_num =
@@ -546,4 +1521,213 @@ mod tests {
         assert_eq!(alloced_block_nums, stored_block_nums);
         assert_eq!(alloced_block_num, stored_block_num)
     }
+
+    fn used_blocks(fs: &FileSystem) -> usize {
+        fs.block_map.vec.iter().filter(|b| *b).count()
+    }
+
+    #[test]
+    fn truncate_reclaims_blocks() {
+        let device = MemoryDevice::new(1024, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
+        let before = used_blocks(&fs);
+        let inode_num = fs.inode_map.alloc(INodeFlags::FILE).unwrap();
+        let data = vec![7u8; 200 * 128];
+        fs.write_at(inode_num, 0, &data).unwrap();
+        assert_eq!(fs.inode_map.get(inode_num).level, 2);
+        fs.truncate(inode_num, 20 * 128 + 5).unwrap();
+        assert_eq!(fs.inode_map.get(inode_num).level, 1);
+        assert!(fs.lookup_block_num_from_offset(inode_num, BlockOffset::new(20)).unwrap().is_some());
+        assert!(fs.lookup_block_num_from_offset(inode_num, BlockOffset::new(21)).unwrap().is_none());
+        // 21 data blocks and the two pointer blocks the level 1 root points at
+        assert_eq!(used_blocks(&fs), before + 23);
+        fs.truncate(inode_num, 30 * 128).unwrap();
+        let mut tail = vec![1; 128];
+        fs.read_at(inode_num, 20 * 128, &mut tail).unwrap();
+        assert_eq!(&tail[.. 5], &[7; 5]);
+        assert!(tail[5 ..].iter().all(|b| *b == 0));
+        fs.truncate(inode_num, 3).unwrap();
+        assert_eq!(fs.inode_map.get(inode_num).level, 0);
+        assert_eq!(used_blocks(&fs), before + 1);
+        fs.free_inode(inode_num).unwrap();
+        assert_eq!(used_blocks(&fs), before);
+        assert_eq!(fs.inode_map.get(inode_num).flags, INodeFlags::FREE);
+    }
+
+    #[test]
+    fn inode_table_packs_inodes() {
+        let disk = MemoryDevice::new(1024, Some(512)).unwrap();
+        let mut fs = FileSystem::new(disk.clone(), Some(1000)).unwrap();
+        assert_eq!(fs.master_block.inodes_per_block(), 4);
+        assert_eq!(fs.master_block.inode_blocks(), 250);
+        let last = (1 .. 1000).map(|_| {
+            fs.make_room(1).unwrap();
+            fs.inode_map.alloc(INodeFlags::FILE).unwrap()
+        }).last();
+        assert_eq!(last, Some(999));
+        assert_eq!(fs.inode_map.alloc(INodeFlags::FILE), None);
+        fs.inode_map.get_mut(998).length = 42;
+        fs.close().unwrap();
+        let fs = FileSystem::read(disk.clone()).unwrap().file_system;
+        assert_eq!(fs.master_block.inode_count(), 1000);
+        assert_eq!(fs.inode_map.get(998).length, 42);
+        assert_eq!(fs.inode_map.get(999).flags, INodeFlags::FILE);
+        let device = MemoryDevice::new(128, Some(512)).unwrap();
+        assert!(FileSystem::new(device, Some(1000)).is_err());
+    }
+
+    #[test]
+    fn only_dirty_block_map_blocks_count() {
+        let disk = MemoryDevice::new(4096, Some(128)).unwrap();
+        let mut fs = FileSystem::new(disk, None).unwrap();
+        assert_eq!(fs.master_block.block_map_blocks(), 5);
+        fs.write().unwrap();
+        assert_eq!(fs.dirty_block_map_blocks(), 0);
+        fs.block_map.set(BlockNumber::new(3200), true);
+        assert_eq!(fs.dirty_block_map_blocks(), 1);
+        fs.block_map.set(BlockNumber::new(3201), true);
+        fs.block_map.set(BlockNumber::new(4000), true);
+        assert_eq!(fs.dirty_block_map_blocks(), 2);
+        fs.write().unwrap();
+        assert_eq!(fs.dirty_block_map_blocks(), 0);
+    }
+
+    #[test]
+    fn mount_falls_back_to_backup() {
+        let disk = MemoryDevice::new(256, Some(128)).unwrap();
+        let mut fs = FileSystem::new(disk.clone(), None).unwrap();
+        assert_eq!(fs.master_block.backups(), vec![BlockNumber::new(128), BlockNumber::new(255)]);
+        let file = fs.create(ROOT_INODE, "kept").unwrap();
+        fs.write_at(file, 0, &[3; 1000]).unwrap();
+        assert!(fs.block_map.is_allocated(BlockNumber::new(128)));
+        fs.close().unwrap();
+        let mut device = disk.clone();
+        device.write(MASTER_BLOCK_NUMBER, &mut [0; 128]).unwrap();
+        let mount = FileSystem::read(device).unwrap();
+        assert_eq!(mount.backup, Some(BlockNumber::new(128)));
+        assert!(! mount.clean_mount);
+        let mut fs = mount.file_system;
+        assert_eq!(fs.lookup(ROOT_INODE, "kept").unwrap(), Some(file));
+        fs.close().unwrap();
+        let mount = FileSystem::read(disk.clone()).unwrap();
+        assert_eq!(mount.backup, None);
+        assert!(mount.clean_mount);
+    }
+
+    #[test]
+    fn alloc_every_block_of_large_map() {
+        const BLOCK_COUNT : u64 = 1 << 21;
+        let mut block_map = BlockMap::new(BLOCK_COUNT);
+        block_map.set(BlockNumber::new(5), true);
+        for i in 0 .. BLOCK_COUNT - 1 {
+            let expected = if i < 5 { i } else { i + 1 };
+            assert_eq!(block_map.alloc().unwrap(), BlockNumber::new(expected));
+        }
+        assert_eq!(block_map.free_blocks(), 0);
+        assert!(block_map.alloc().is_err());
+        block_map.free(BlockNumber::new(70_000));
+        block_map.free(BlockNumber::new(3));
+        assert_eq!(block_map.free_blocks(), 2);
+        assert_eq!(block_map.alloc().unwrap(), BlockNumber::new(3));
+        assert_eq!(block_map.alloc().unwrap(), BlockNumber::new(70_000));
+        assert!(block_map.alloc().is_err());
+    }
+
+    #[test]
+    fn alloc_near_and_run() {
+        let mut block_map = BlockMap::new(64);
+        for i in [10, 11, 13, 20, 21, 22, 23].iter() {
+            block_map.set(BlockNumber::new(*i), true);
+        }
+        assert_eq!(block_map.alloc_near(BlockNumber::new(9)).unwrap(), BlockNumber::new(9));
+        assert_eq!(block_map.alloc_near(BlockNumber::new(10)).unwrap(), BlockNumber::new(12));
+        assert_eq!(block_map.alloc_run(5).unwrap(), BlockNumber::new(14));
+        assert_eq!(block_map.alloc_run(40).unwrap(), BlockNumber::new(24));
+        assert_eq!(block_map.alloc_run(9).unwrap(), BlockNumber::new(0));
+        assert!(block_map.alloc_run(2).is_err());
+        assert_eq!(block_map.alloc().unwrap(), BlockNumber::new(19));
+    }
+
+    #[test]
+    fn sequential_writes_stay_contiguous() {
+        let device = MemoryDevice::new(1024, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
+        let first = fs.create(ROOT_INODE, "first").unwrap();
+        let second = fs.create(ROOT_INODE, "second").unwrap();
+        let third = fs.create(ROOT_INODE, "third").unwrap();
+        fs.write_at(first, 0, &[1; 4 * 128]).unwrap();
+        fs.write_at(second, 0, &[2; 4 * 128]).unwrap();
+        fs.truncate(second, 0).unwrap();
+        fs.write_at(third, 0, &[3; 128]).unwrap();
+        // Without the hint the append would land after `third`
+        fs.write_at(first, 4 * 128, &[1; 4 * 128]).unwrap();
+        let blocks = (0 .. 8)
+            .map(|i| fs.lookup_block_num_from_offset(first, BlockOffset::new(i)).unwrap().unwrap())
+            .collect::<Vec<_>>();
+        for pair in blocks.windows(2) {
+            assert_eq!(pair[1].number, pair[0].number + 1);
+        }
+    }
+
+    #[test]
+    fn small_cache_writes_back() {
+        let disk = MemoryDevice::new(1024, Some(128)).unwrap();
+        let mut fs = FileSystem::new(disk.clone(), None).unwrap();
+        fs.set_cache_capacity(4).unwrap();
+        let file = fs.create(ROOT_INODE, "big").unwrap();
+        let data = (0 .. 200 * 128).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        fs.write_at(file, 0, &data).unwrap();
+        // Only the dirty pointer blocks can outgrow the capacity until they are journaled
+        assert!(fs.cache.len() > 4);
+        fs.write().unwrap();
+        assert!(fs.cache.len() <= 4);
+        let mut out = vec![0; data.len()];
+        fs.read_at(file, 0, &mut out).unwrap();
+        assert_eq!(out, data);
+        fs.close().unwrap();
+        let mut fs = FileSystem::read(disk.clone()).unwrap().file_system;
+        let mut out = vec![0; data.len()];
+        fs.read_at(file, 0, &mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn write_skips_clean_metadata() {
+        let disk = MemoryDevice::new(1024, Some(128)).unwrap();
+        let mut fs = FileSystem::new(disk.clone(), None).unwrap();
+        fs.write().unwrap();
+        let before = fs.stats();
+        fs.write().unwrap();
+        assert_eq!(fs.stats(), before);
+        fs.inode_map.get_mut(5).length = 42;
+        fs.write().unwrap();
+        // The inode table block is logged and checkpointed, the header is written and cleared
+        assert_eq!(fs.stats().blocks_written - before.blocks_written, 4);
+        fs.close().unwrap();
+        let fs = FileSystem::read(disk.clone()).unwrap().file_system;
+        assert_eq!(fs.inode_map.get(5).length, 42);
+    }
+
+    #[test]
+    fn images_describe_themselves() {
+        let path = env::temp_dir().join(format!("umbrella-image-{}", process::id()));
+        let device = RawDevice::create(&path, 512, Some(256)).unwrap();
+        FileSystem::new(device, None).unwrap().close().unwrap();
+        let device = open_image(&path).unwrap();
+        assert_eq!(device.config().block_size, 256);
+        assert_eq!(device.config().block_count, 512);
+        let mut device = open_image(&path).unwrap();
+        device.write(MASTER_BLOCK_NUMBER, &mut [0; 256]).unwrap();
+        let mount = FileSystem::read(open_image(&path).unwrap()).unwrap();
+        assert_eq!(mount.backup, Some(BlockNumber::new(256)));
+        fs::remove_file(&path).unwrap();
+        // Nothing on a blank image says how big its blocks are except for its name
+        let name = env::temp_dir().join(format!("umbrella-blank-{}", process::id()));
+        let name = name.to_string_lossy();
+        FileDevice::create(&name, 16, Some(200)).unwrap();
+        let file = format!("{}.200.dev", name);
+        assert_eq!(open_image(&file).unwrap().config().block_size, 200);
+        fs::remove_file(&file).unwrap();
+        assert!(open_image(&path).is_err());
+    }
 }
@@ -0,0 +1,559 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display, Formatter};
+
+use block_number::{BlockNumber, MASTER_BLOCK_NUMBER, Sequence};
+use device;
+use dir::{DirEntry, ROOT_INODE};
+use extent::{self, Extent};
+use fs::{FileSystem, INodeFlags, INodeFormat, MasterBlockFlags};
+
+/// Something `fsck` found wrong with a file system.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// An inode whose tree is deeper than any device could address
+    BadLevel { inode: usize, level: u8 },
+    /// A pointer to a block past the end of the device or inside of the metadata region
+    OutOfRange { inode: usize, block: BlockNumber },
+    /// A block that is referenced more than once
+    DoubleAllocated { block: BlockNumber, first: usize, second: usize },
+    /// A block that is in use but marked free in the block map
+    Unmarked { block: BlockNumber },
+    /// A block that is marked allocated in the block map but nothing references
+    Leaked { block: BlockNumber },
+    /// A directory entry that points at a free or nonexistent inode, or a wrong `.` or `..`
+    BadEntry { dir: usize, name: String, inode: usize },
+    /// A `.` or `..` entry that is missing
+    MissingEntry { dir: usize, name: String },
+    /// An allocated inode that cannot be reached from the root
+    Orphan { inode: usize },
+    /// The root inode is not a directory so the namespace could not be checked
+    BadRoot,
+    /// A copy of the master block that does not match the master block
+    BadBackup { block: BlockNumber }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        use self::Problem::*;
+        match *self {
+            BadLevel { inode, level } =>
+                write!(f, "inode {} has an impossible tree level {}", inode, level),
+            OutOfRange { inode, block } =>
+                write!(f, "inode {} points at block {} which is out of range", inode, block),
+            DoubleAllocated { block, first, second } =>
+                write!(f, "block {} is claimed by inode {} and inode {}", block, first, second),
+            Unmarked { block } =>
+                write!(f, "block {} is in use but marked free", block),
+            Leaked { block } =>
+                write!(f, "block {} is marked allocated but unused", block),
+            BadEntry { dir, ref name, inode } =>
+                write!(f, "directory {} has a bad entry {} -> inode {}", dir, name, inode),
+            MissingEntry { dir, ref name } =>
+                write!(f, "directory {} is missing its {} entry", dir, name),
+            Orphan { inode } =>
+                write!(f, "inode {} is not reachable from the root", inode),
+            BadRoot =>
+                write!(f, "the root inode is not a directory"),
+            BadBackup { block } =>
+                write!(f, "the backup master block at block {} is out of date", block)
+        }
+    }
+}
+
+pub struct Report {
+    pub problems: Vec<Problem>,
+    pub repaired: bool
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        let verb = if self.repaired { "repaired" } else { "found" };
+        writeln!(f, "fsck: {} problem(s) {}", self.problems.len(), verb)
+    }
+}
+
+// The extents and nodes that are left of a damaged extent tree
+struct Rebuild {
+    inode:   usize,
+    extents: Vec<Extent>,
+    nodes:   Vec<BlockNumber>
+}
+
+// Where a block pointer lives so that it can be cleared during repair
+#[derive(Copy, Clone)]
+enum Slot {
+    INode(usize, usize),
+    Pointers(BlockNumber, usize)
+}
+
+impl FileSystem {
+    fn clear_slot(&mut self, slot: Slot) -> device::Result<()> {
+        self.make_room(1)?;
+        match slot {
+            Slot::INode(inode_num, i) => {
+                self.inode_map.get_mut(inode_num).block_ptrs[i] = MASTER_BLOCK_NUMBER
+            }
+            Slot::Pointers(block_num, i) => {
+                self.cache.read_pointers_mut(block_num)?.set(i, MASTER_BLOCK_NUMBER)
+            }
+        }
+        Ok(())
+    }
+
+    fn in_range(&self, block_num: BlockNumber) -> bool {
+        ! self.master_block.is_reserved(block_num) &&
+            block_num.number < self.master_block.block_count()
+    }
+
+    fn is_allocated_inode(&self, inode_num: usize) -> bool {
+        inode_num < self.master_block.inode_count() as usize &&
+            ! self.inode_map.get(inode_num).flags.contains(INodeFlags::FREE)
+    }
+
+    // Walks the pointer tree of every allocated inode recording which inode owns each block.
+    // Damaged extent trees are only collected in `rebuilds` since rebuilding one may allocate.
+    fn check_trees(&mut self, repair: bool, problems: &mut Vec<Problem>,
+                   rebuilds: &mut Vec<Rebuild>) -> device::Result<HashMap<BlockNumber, usize>> {
+        let bnpb = self.cache.device.block_numbers_per_block();
+        let mut owners = HashMap::new();
+        for inode_num in 0 .. self.master_block.inode_count() as usize {
+            if ! self.is_allocated_inode(inode_num) {
+                continue
+            }
+            let (level, block_ptrs) = {
+                let inode = self.inode_map.get(inode_num);
+                (inode.level, inode.block_ptrs)
+            };
+            let addressable = bnpb.checked_pow(level as u32)
+                .and_then(|bnpl| bnpl.checked_mul(block_ptrs.len()));
+            if addressable.is_none() {
+                problems.push(Problem::BadLevel { inode: inode_num, level });
+                if repair {
+                    let inode = self.inode_map.get_mut(inode_num);
+                    inode.level = 0;
+                    inode.length = 0;
+                    inode.block_ptrs = [MASTER_BLOCK_NUMBER; 8];
+                }
+                continue
+            }
+            if self.master_block.format() == INodeFormat::Extents {
+                if let Some(rebuild) = self.check_extents(inode_num, problems, &mut owners)? {
+                    if repair {
+                        rebuilds.push(rebuild);
+                    }
+                }
+                continue
+            }
+            let mut stack = block_ptrs.iter()
+                .enumerate()
+                .map(|(i, block_num)| (*block_num, Slot::INode(inode_num, i), level))
+                .collect::<Vec<_>>();
+            while let Some((block_num, slot, level)) = stack.pop() {
+                if block_num == MASTER_BLOCK_NUMBER {
+                    continue
+                }
+                if ! self.in_range(block_num) {
+                    problems.push(Problem::OutOfRange { inode: inode_num, block: block_num });
+                    if repair {
+                        self.clear_slot(slot)?;
+                    }
+                    continue
+                }
+                if let Some(first) = owners.get(&block_num).cloned() {
+                    problems.push(Problem::DoubleAllocated {
+                        block: block_num,
+                        first,
+                        second: inode_num
+                    });
+                    if repair {
+                        self.clear_slot(slot)?;
+                    }
+                    continue
+                }
+                owners.insert(block_num, inode_num);
+                if level > 0 {
+                    let children = self.cache.read_pointers(block_num)?.to_vec();
+                    for (i, child) in children.into_iter().enumerate() {
+                        stack.push((child, Slot::Pointers(block_num, i), level - 1));
+                    }
+                }
+            }
+        }
+        Ok(owners)
+    }
+
+    // Walks the extent tree of `inode_num`. When extents or nodes point out of range or at
+    // blocks that are already owned, the tree the remaining ones make up is returned.
+    fn check_extents(&mut self, inode_num: usize, problems: &mut Vec<Problem>,
+                     owners: &mut HashMap<BlockNumber, usize>) -> device::Result<Option<Rebuild>> {
+        let (level, root) = {
+            let inode = self.inode_map.get(inode_num);
+            (inode.level, inode.block_ptrs)
+        };
+        let mut extents = vec![];
+        let mut nodes = vec![];
+        let mut damaged = false;
+        let mut stack = extent::entries(&root)
+            .into_iter()
+            .rev()
+            .map(|extent| (extent, level))
+            .collect::<Vec<(Extent, u8)>>();
+        while let Some((extent, level)) = stack.pop() {
+            let count = if level == 0 { extent.length } else { 1 };
+            let blocks = (0 .. count).map(|i| BlockNumber::new(extent.start.number + i));
+            let mut bad = None;
+            for block_num in blocks.clone() {
+                if ! self.in_range(block_num) {
+                    bad = Some(Problem::OutOfRange { inode: inode_num, block: block_num });
+                } else if let Some(first) = owners.get(&block_num).cloned() {
+                    bad = Some(Problem::DoubleAllocated {
+                        block: block_num,
+                        first,
+                        second: inode_num
+                    });
+                }
+                if bad.is_some() {
+                    break
+                }
+            }
+            if let Some(problem) = bad {
+                problems.push(problem);
+                damaged = true;
+                continue
+            }
+            for block_num in blocks {
+                owners.insert(block_num, inode_num);
+            }
+            if level == 0 {
+                extents.push(extent);
+            } else {
+                nodes.push(extent.start);
+                let children = extent::entries(&self.cache.read_pointers(extent.start)?.to_vec());
+                stack.extend(children.into_iter().rev().map(|child| (child, level - 1)));
+            }
+        }
+        if damaged {
+            Ok(Some(Rebuild { inode: inode_num, extents, nodes }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Cross checks the block map against the reserved blocks and the owners of every block.
+    fn check_block_map(&mut self, owners: &HashMap<BlockNumber, usize>, repair: bool,
+                       problems: &mut Vec<Problem>) {
+        let block_count = self.master_block.block_count();
+        let backups = self.master_block.backups();
+        let data_start = self.master_block.data_start();
+        for block_num in Sequence::new(MASTER_BLOCK_NUMBER, block_count) {
+            let used = block_num < data_start || backups.contains(&block_num)
+                || owners.contains_key(&block_num);
+            let allocated = self.block_map.is_allocated(block_num);
+            if used && ! allocated {
+                problems.push(Problem::Unmarked { block: block_num });
+            } else if ! used && allocated {
+                problems.push(Problem::Leaked { block: block_num });
+            } else {
+                continue
+            }
+            if repair {
+                self.block_map.set(block_num, used);
+            }
+        }
+    }
+
+    // Validates every directory entry below `dir`, whose `..` should be `parent`, and records
+    // every inode it reaches.
+    fn check_entries(&mut self, dir: usize, parent: usize, reached: &mut HashSet<usize>,
+                     repair: bool, problems: &mut Vec<Problem>) -> device::Result<()> {
+        let mut queue = VecDeque::new();
+        reached.insert(dir);
+        queue.push_back((dir, parent));
+        while let Some((dir, parent)) = queue.pop_front() {
+            let entries = self.read_dir(dir)?;
+            for &(name, expected) in [(".", dir), ("..", parent)].iter() {
+                match entries.iter().find(|entry| entry.name == name) {
+                    Some(entry) if entry.inode == expected => continue,
+                    Some(entry) => problems.push(Problem::BadEntry {
+                        dir,
+                        name: name.to_string(),
+                        inode: entry.inode
+                    }),
+                    None => problems.push(Problem::MissingEntry { dir, name: name.to_string() })
+                }
+                if repair {
+                    self.set_entry(dir, &DirEntry::new(expected, name))?;
+                }
+            }
+            for entry in entries {
+                if entry.name == "." || entry.name == ".." {
+                    continue
+                }
+                if ! self.is_allocated_inode(entry.inode) {
+                    problems.push(Problem::BadEntry {
+                        dir,
+                        name: entry.name.clone(),
+                        inode: entry.inode
+                    });
+                    if repair {
+                        self.remove_entry(dir, &entry.name)?;
+                    }
+                } else if reached.insert(entry.inode) && self.is_dir(entry.inode) {
+                    queue.push_back((entry.inode, dir));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Validates every directory entry and finds inodes that are not reachable from the root.
+    fn check_namespace(&mut self, repair: bool, problems: &mut Vec<Problem>) -> device::Result<()> {
+        if ! self.is_allocated_inode(ROOT_INODE) || ! self.is_dir(ROOT_INODE) {
+            problems.push(Problem::BadRoot);
+            return Ok(())
+        }
+        let mut reached = HashSet::new();
+        self.check_entries(ROOT_INODE, ROOT_INODE, &mut reached, repair, problems)?;
+        let orphans = (0 .. self.master_block.inode_count() as usize)
+            .filter(|inode_num| self.is_allocated_inode(*inode_num) && ! reached.contains(inode_num))
+            .collect::<Vec<_>>();
+        // An orphan that another orphaned directory still lists comes back along with it
+        let mut listed = HashSet::new();
+        for &orphan in &orphans {
+            if ! self.is_dir(orphan) {
+                continue
+            }
+            for entry in self.read_dir(orphan)? {
+                if entry.name != "." && entry.name != ".." {
+                    listed.insert(entry.inode);
+                }
+            }
+        }
+        let (tops, rest) : (Vec<_>, Vec<_>) =
+            orphans.into_iter().partition(|orphan| ! listed.contains(orphan));
+        for orphan in tops.into_iter().chain(rest) {
+            if reached.contains(&orphan) {
+                continue
+            }
+            problems.push(Problem::Orphan { inode: orphan });
+            reached.insert(orphan);
+            if repair {
+                // Orphans are reattached to the root under their inode number, followed by a
+                // counter if that name is already taken
+                let mut name = format!("#{}", orphan);
+                let mut suffix = 0;
+                while self.lookup(ROOT_INODE, &name)?.is_some() {
+                    suffix += 1;
+                    name = format!("#{}.{}", orphan, suffix);
+                }
+                self.add_entry(ROOT_INODE, &DirEntry::new(orphan, &name))?;
+            }
+            if ! self.is_dir(orphan) {
+                continue
+            }
+            let parent = if repair {
+                self.set_entry(orphan, &DirEntry::new(ROOT_INODE, ".."))?;
+                ROOT_INODE
+            } else {
+                // Its `..` is only wrong once it is reattached
+                self.lookup(orphan, "..")?.unwrap_or(ROOT_INODE)
+            };
+            self.check_entries(orphan, parent, &mut reached, repair, problems)?;
+        }
+        Ok(())
+    }
+
+    // Compares every backup against the master block as `close` would write it.
+    fn check_backups(&mut self, repair: bool, problems: &mut Vec<Problem>) -> device::Result<()> {
+        let mut expected = self.master_block.clone();
+        expected.flags.insert(MasterBlockFlags::SYNCED);
+        let block_size = self.cache.device.config().block_size as usize;
+        let mut expected_vec = vec![0; block_size];
+        expected.encode(&mut expected_vec);
+        let mut mb_vec = vec![0; block_size];
+        for backup in self.master_block.backups() {
+            self.cache.device.read(backup, &mut mb_vec)?;
+            if mb_vec != expected_vec {
+                problems.push(Problem::BadBackup { block: backup });
+                if repair {
+                    self.cache.device.write(backup, &mut expected_vec.clone())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the consistency of the backup master blocks, the block map, every inode's pointer
+    /// tree, and the directory hierarchy. When `repair` is set every problem is also fixed: bad
+    /// pointers are cleared (the first owner of a doubly allocated block keeps it), the block map
+    /// is rebuilt from what is actually referenced, bad entries are dropped, orphans are linked
+    /// into the root, and stale backups are rewritten.
+    pub fn fsck(&mut self, repair: bool) -> device::Result<Report> {
+        let mut problems = vec![];
+        self.check_backups(repair, &mut problems)?;
+        let mut rebuilds = vec![];
+        let owners = self.check_trees(repair, &mut problems, &mut rebuilds)?;
+        self.check_block_map(&owners, repair, &mut problems);
+        // Only a repaired block map can hand out the nodes a rebuilt tree may need
+        for Rebuild { inode, extents, nodes } in rebuilds {
+            self.make_room(nodes.len() + 1)?;
+            self.write_extents(inode, &extents, nodes)?;
+        }
+        self.check_namespace(repair, &mut problems)?;
+        Ok(Report { problems, repaired: repair })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use block_number::{BlockOffset};
+    use device::MemoryDevice;
+    use super::*;
+
+    fn fixture() -> (FileSystem, usize, usize) {
+        let device = MemoryDevice::new(512, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
+        let dir = fs.mkdir(ROOT_INODE, "projects").unwrap();
+        let file = fs.create(dir, "notes.txt").unwrap();
+        fs.write_at(file, 0, &vec![1; 20 * 128]).unwrap();
+        (fs, dir, file)
+    }
+
+    #[test]
+    fn clean_file_system() {
+        let (mut fs, _, _) = fixture();
+        let report = fs.fsck(false).unwrap();
+        assert!(report.is_clean(), "{}", report);
+    }
+
+    #[test]
+    fn detects_and_repairs_blocks() {
+        let (mut fs, _, file) = fixture();
+        let other = fs.create(ROOT_INODE, "other").unwrap();
+        let shared = fs.lookup_block_num_from_offset(file, BlockOffset::new(3)).unwrap().unwrap();
+        let leaked = fs.block_map.alloc().unwrap();
+        fs.inode_map.get_mut(other).block_ptrs[0] = shared;
+        fs.inode_map.get_mut(other).block_ptrs[1] = BlockNumber::new(100_000);
+        fs.block_map.free(BlockNumber::new(1));
+        let report = fs.fsck(true).unwrap();
+        assert!(report.problems.contains(&Problem::DoubleAllocated {
+            block: shared,
+            first: file,
+            second: other
+        }));
+        assert!(report.problems.contains(&Problem::OutOfRange {
+            inode: other,
+            block: BlockNumber::new(100_000)
+        }));
+        assert!(report.problems.contains(&Problem::Leaked { block: leaked }));
+        assert!(report.problems.contains(&Problem::Unmarked { block: BlockNumber::new(1) }));
+        let report = fs.fsck(false).unwrap();
+        assert!(report.is_clean(), "{}", report);
+    }
+
+    #[test]
+    fn detects_and_repairs_directories() {
+        let (mut fs, dir, file) = fixture();
+        fs.remove_entry(dir, "notes.txt").unwrap();
+        fs.set_entry(dir, &DirEntry::new(file, "..")).unwrap();
+        fs.add_entry(dir, &DirEntry::new(42, "ghost")).unwrap();
+        let report = fs.fsck(true).unwrap();
+        assert!(report.problems.contains(&Problem::BadEntry {
+            dir,
+            name: "..".to_string(),
+            inode: file
+        }));
+        assert!(report.problems.contains(&Problem::BadEntry {
+            dir,
+            name: "ghost".to_string(),
+            inode: 42
+        }));
+        assert!(report.problems.contains(&Problem::Orphan { inode: file }));
+        assert_eq!(fs.lookup(ROOT_INODE, &format!("#{}", file)).unwrap(), Some(file));
+        let report = fs.fsck(false).unwrap();
+        assert!(report.is_clean(), "{}", report);
+        // A second orphan under a name that is already taken gets a suffix
+        fs.remove_entry(ROOT_INODE, &format!("#{}", file)).unwrap();
+        fs.add_entry(ROOT_INODE, &DirEntry::new(dir, &format!("#{}", file))).unwrap();
+        fs.remove_entry(ROOT_INODE, "projects").unwrap();
+        fs.fsck(true).unwrap();
+        assert_eq!(fs.lookup(ROOT_INODE, &format!("#{}.1", file)).unwrap(), Some(file));
+        let report = fs.fsck(false).unwrap();
+        assert!(report.is_clean(), "{}", report);
+    }
+
+    #[test]
+    fn reattaches_nested_orphans_once() {
+        let (mut fs, dir, file) = fixture();
+        let inner = fs.mkdir(dir, "inner").unwrap();
+        let deeper = fs.mkdir(inner, "deeper").unwrap();
+        // `inner` now lives in `deeper` even though its inode number is lower
+        fs.remove_entry(inner, "deeper").unwrap();
+        fs.remove_entry(dir, "inner").unwrap();
+        fs.add_entry(deeper, &DirEntry::new(inner, "inner")).unwrap();
+        fs.set_entry(inner, &DirEntry::new(deeper, "..")).unwrap();
+        fs.remove_entry(ROOT_INODE, "projects").unwrap();
+        let orphans = vec![Problem::Orphan { inode: dir }, Problem::Orphan { inode: deeper }];
+        assert_eq!(fs.fsck(false).unwrap().problems, orphans);
+        assert_eq!(fs.fsck(true).unwrap().problems, orphans);
+        assert_eq!(fs.lookup(ROOT_INODE, &format!("#{}", dir)).unwrap(), Some(dir));
+        assert_eq!(fs.lookup(ROOT_INODE, &format!("#{}", deeper)).unwrap(), Some(deeper));
+        assert_eq!(fs.lookup(ROOT_INODE, &format!("#{}", inner)).unwrap(), None);
+        assert_eq!(fs.lookup(dir, "notes.txt").unwrap(), Some(file));
+        assert_eq!(fs.lookup(deeper, "inner").unwrap(), Some(inner));
+        assert_eq!(fs.lookup(inner, "..").unwrap(), Some(deeper));
+        let report = fs.fsck(false).unwrap();
+        assert!(report.is_clean(), "{}", report);
+    }
+
+    #[test]
+    fn detects_and_repairs_backups() {
+        let (mut fs, _, _) = fixture();
+        let backup = fs.master_block.backups()[0];
+        fs.cache.device.write(backup, &mut [0; 128]).unwrap();
+        let report = fs.fsck(true).unwrap();
+        assert_eq!(report.problems, vec![Problem::BadBackup { block: backup }]);
+        let report = fs.fsck(false).unwrap();
+        assert!(report.is_clean(), "{}", report);
+    }
+
+    #[test]
+    fn detects_and_repairs_extents() {
+        let device = MemoryDevice::new(512, Some(128)).unwrap();
+        let mut fs = FileSystem::new_with_format(device, None, INodeFormat::Extents).unwrap();
+        let file = fs.create(ROOT_INODE, "striped").unwrap();
+        for i in 0 .. 12 {
+            fs.write_at(file, i * 2 * 128, &[1; 128]).unwrap();
+        }
+        let other = fs.create(ROOT_INODE, "other").unwrap();
+        fs.write_at(other, 0, &[2; 128]).unwrap();
+        assert!(fs.fsck(false).unwrap().is_clean());
+        let (mut extents, nodes) = fs.read_extents(other).unwrap();
+        let (striped, _) = fs.read_extents(file).unwrap();
+        extents.push(Extent { logical: 5, start: striped[3].start, length: 1 });
+        extents.push(Extent { logical: 9, start: BlockNumber::new(100_000), length: 2 });
+        fs.write_extents(other, &extents, nodes).unwrap();
+        let report = fs.fsck(true).unwrap();
+        assert!(report.problems.contains(&Problem::DoubleAllocated {
+            block: striped[3].start,
+            first: file,
+            second: other
+        }));
+        assert!(report.problems.contains(&Problem::OutOfRange {
+            inode: other,
+            block: BlockNumber::new(100_000)
+        }));
+        assert_eq!(fs.read_extents(other).unwrap().0.len(), 1);
+        let report = fs.fsck(false).unwrap();
+        assert!(report.is_clean(), "{}", report);
+    }
+}
@@ -0,0 +1,364 @@
+use block_number::{BlockNumber, MASTER_BLOCK_NUMBER};
+use checksum::{self, crc32c, CHECKSUM_LEN};
+use device::{self, BlockDevice, Error};
+use encoding::{get_u32, get_u64, put_u32, put_u64};
+
+// The journal is a contiguous run of blocks reserved by the master block. A transaction is
+// logged as a descriptor followed by copies of its blocks:
+//
+//   | magic: "UMBJ" | committed: u8 | reserved: [u8; 3] | count: u32 | reserved: [u8; 4] |
+//   | (target: u64 | source: u64 | length: u32 | checksum: u32) * count | checksum: u32 |
+//
+// Every entry copies the `length` blocks at `source` over the ones at `target` and holds their
+// CRC32C. The sources are normally the blocks right after the descriptor but a transaction that
+// is too big for the journal can be staged in free blocks elsewhere, see `stage`. The descriptor
+// takes up as many blocks as its entries need and is sealed as a whole. A transaction is durable
+// the moment a committed descriptor hits the disk, which only happens once every logged block is
+// on it. Until the descriptor is cleared again `replay` will copy the logged blocks over their
+// targets, which is idempotent, and it copies the master block last of all so that a transaction
+// can switch the file system over to a new layout. A descriptor that does not validate was torn
+// before it was committed.
+const MAGIC : &[u8; 4] = b"UMBJ";
+const HEADER_LEN : usize = 16;
+const ENTRY_LEN : usize = 24;
+
+/// A set of block writes that should reach the device all at once or not at all.
+#[derive(Default)]
+pub struct Transaction {
+    blocks: Vec<(BlockNumber, Vec<u8>)>
+}
+
+impl Transaction {
+    pub fn new() -> Transaction {
+        Transaction { blocks: vec![] }
+    }
+
+    pub fn write(&mut self, block_num: BlockNumber, block: Vec<u8>) {
+        self.blocks.push((block_num, block));
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Writes every block straight to its target without any of the guarantees of a commit.
+    pub (crate) fn apply(&self, device: &mut dyn BlockDevice) -> device::Result<()> {
+        device.write_vectored(&self.blocks)
+    }
+}
+
+// A run of logged blocks and where it goes.
+#[derive(Copy, Clone)]
+struct Entry {
+    target:   BlockNumber,
+    source:   BlockNumber,
+    length:   u64,
+    checksum: u32
+}
+
+// How many blocks the descriptor of a transaction of `count` entries takes up.
+fn descriptor_blocks(block_size: u16, count: usize) -> usize {
+    (HEADER_LEN + count * ENTRY_LEN + CHECKSUM_LEN).div_ceil(block_size as usize)
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Journal {
+    start:  BlockNumber,
+    blocks: u64
+}
+
+impl Journal {
+    pub fn new(start: BlockNumber, blocks: u64) -> Journal {
+        Journal { start, blocks }
+    }
+
+    pub fn start(&self) -> BlockNumber {
+        self.start
+    }
+
+    /// How many blocks a single transaction can hold next to its descriptor.
+    pub fn capacity(&self, block_size: u16) -> usize {
+        let blocks = self.blocks as usize;
+        let mut count = blocks * block_size as usize / (block_size as usize + ENTRY_LEN);
+        while count > 0 && count + descriptor_blocks(block_size, count) > blocks {
+            count -= 1;
+        }
+        count
+    }
+
+    /// How many runs of consecutive blocks a staged transaction can be made of.
+    pub fn max_runs(&self, block_size: u16) -> usize {
+        let bytes = self.blocks as usize * block_size as usize;
+        bytes.saturating_sub(HEADER_LEN + CHECKSUM_LEN) / ENTRY_LEN
+    }
+
+    fn block(&self, i: usize) -> BlockNumber {
+        BlockNumber::new(self.start.number + i as u64)
+    }
+
+    fn write_descriptor(&self, device: &mut dyn BlockDevice, entries: &[Entry]) -> device::Result<()> {
+        let block_size = device.config().block_size;
+        let blocks = descriptor_blocks(block_size, entries.len());
+        let mut descriptor = vec![0; blocks * block_size as usize];
+        descriptor[0 .. 4].copy_from_slice(MAGIC);
+        descriptor[4] = ! entries.is_empty() as u8;
+        put_u32(&mut descriptor, 8, entries.len() as u32);
+        for (n, entry) in entries.iter().enumerate() {
+            let at = HEADER_LEN + n * ENTRY_LEN;
+            put_u64(&mut descriptor, at, entry.target.number);
+            put_u64(&mut descriptor, at + 8, entry.source.number);
+            put_u32(&mut descriptor, at + 16, entry.length as u32);
+            put_u32(&mut descriptor, at + 20, entry.checksum);
+        }
+        checksum::seal(&mut descriptor);
+        device.write_blocks(self.start, blocks as u64, &descriptor)?;
+        device.sync()
+    }
+
+    fn read_descriptor(&self, device: &dyn BlockDevice) -> device::Result<Vec<Entry>> {
+        let block_size = device.config().block_size;
+        let mut header = vec![0; block_size as usize];
+        device.read(self.start, &mut header)?;
+        if &header[0 .. 4] != MAGIC || header[4] == 0 {
+            return Ok(vec![])
+        }
+        let count = get_u32(&header, 8) as usize;
+        if count > self.max_runs(block_size) {
+            return Ok(vec![])
+        }
+        let blocks = descriptor_blocks(block_size, count);
+        let mut descriptor = vec![0; blocks * block_size as usize];
+        device.read_blocks(self.start, blocks as u64, &mut descriptor)?;
+        if ! checksum::verify(&descriptor) {
+            return Ok(vec![])
+        }
+        let entries = (0 .. count)
+            .map(|n| {
+                let at = HEADER_LEN + n * ENTRY_LEN;
+                Entry {
+                    target:   BlockNumber::new(get_u64(&descriptor, at)),
+                    source:   BlockNumber::new(get_u64(&descriptor, at + 8)),
+                    length:   get_u32(&descriptor, at + 16) as u64,
+                    checksum: get_u32(&descriptor, at + 20)
+                }
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    // Copies the logged blocks over their targets and then clears the descriptor. Nothing is
+    // copied unless every logged block matches its checksum.
+    fn checkpoint(&self, device: &mut dyn BlockDevice, entries: &[Entry]) -> device::Result<()> {
+        let block_size = device.config().block_size as usize;
+        let mut blocks = vec![];
+        for entry in entries {
+            let mut logged = vec![0; entry.length as usize * block_size];
+            device.read_blocks(entry.source, entry.length, &mut logged)?;
+            if crc32c(&logged) != entry.checksum {
+                return Err(Error::Corrupt { block: entry.source, kind: "journal" })
+            }
+            for (i, block) in logged.chunks(block_size).enumerate() {
+                blocks.push((BlockNumber::new(entry.target.number + i as u64), block.to_vec()));
+            }
+        }
+        let (master, blocks) : (Vec<_>, Vec<_>) = blocks.into_iter()
+            .partition(|&(target, _)| target == MASTER_BLOCK_NUMBER);
+        device.write_vectored(&blocks)?;
+        device.sync()?;
+        if ! master.is_empty() {
+            device.write_vectored(&master)?;
+            device.sync()?;
+        }
+        self.write_descriptor(device, &[])
+    }
+
+    // Writes every block of a transaction to the slot next to it and commits them without
+    // checkpointing. Blocks with consecutive targets in consecutive slots share an entry.
+    fn log(&self, device: &mut dyn BlockDevice, blocks: &[(BlockNumber, Vec<u8>)],
+           slots: &[BlockNumber]) -> device::Result<Vec<Entry>> {
+        let block_size = device.config().block_size;
+        let mut order = (0 .. blocks.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| blocks[*i].0);
+        let mut runs : Vec<(Entry, Vec<u8>)> = vec![];
+        for (i, slot) in order.into_iter().zip(slots) {
+            let (target, ref block) = blocks[i];
+            if let Some(&mut (ref mut entry, ref mut logged)) = runs.last_mut() {
+                if entry.target.number + entry.length == target.number
+                    && entry.source.number + entry.length == slot.number {
+                    entry.length += 1;
+                    logged.extend_from_slice(block);
+                    continue
+                }
+            }
+            let entry = Entry { target, source: *slot, length: 1, checksum: 0 };
+            runs.push((entry, block.clone()));
+        }
+        if descriptor_blocks(block_size, runs.len()) > self.blocks as usize {
+            let err_msg = format!(
+                "a transaction of [{}] runs does not fit into the journal, which holds [{}]",
+                runs.len(),
+                self.max_runs(block_size)
+            );
+            return Err(Error::Size(err_msg))
+        }
+        let mut entries = vec![];
+        for (mut entry, logged) in runs {
+            device.write_blocks(entry.source, entry.length, &logged)?;
+            entry.checksum = crc32c(&logged);
+            entries.push(entry);
+        }
+        device.sync()?;
+        self.write_descriptor(device, &entries)?;
+        Ok(entries)
+    }
+
+    /// Durably applies `transaction` to the device. A transaction that holds more blocks than
+    /// the journal is refused before anything is written.
+    pub fn commit(&self, device: &mut dyn BlockDevice, transaction: Transaction) -> device::Result<()> {
+        if transaction.is_empty() {
+            return Ok(())
+        }
+        let block_size = device.config().block_size;
+        let capacity = self.capacity(block_size);
+        if transaction.len() > capacity {
+            let err_msg = format!(
+                "a transaction of [{}] blocks does not fit into the journal, which holds [{}]",
+                transaction.len(),
+                capacity
+            );
+            return Err(Error::Size(err_msg))
+        }
+        let first = descriptor_blocks(block_size, transaction.len());
+        let slots = (first .. first + transaction.len()).map(|i| self.block(i)).collect::<Vec<_>>();
+        let entries = self.log(device, &transaction.blocks, &slots)?;
+        self.checkpoint(device, &entries)
+    }
+
+    /// Commits `transaction` like `commit` does but with its blocks staged at `staging` instead
+    /// of inside of the journal, so only its descriptor has to fit. The staging blocks must not
+    /// be used by anything else until the transaction is checkpointed, which is left to `replay`.
+    pub (crate) fn stage(&self, device: &mut dyn BlockDevice, transaction: Transaction,
+                         staging: &[BlockNumber]) -> device::Result<()> {
+        if staging.len() < transaction.len() {
+            let err_msg = format!(
+                "a transaction of [{}] blocks cannot be staged in [{}] blocks",
+                transaction.len(),
+                staging.len()
+            );
+            return Err(Error::Size(err_msg))
+        }
+        self.log(device, &transaction.blocks, staging).map(|_| ())
+    }
+
+    /// Finishes a transaction that was committed but not checkpointed before a crash.
+    /// Returns the number of blocks that were recovered.
+    pub fn replay(&self, device: &mut dyn BlockDevice) -> device::Result<usize> {
+        let entries = self.read_descriptor(device)?;
+        if ! entries.is_empty() {
+            self.checkpoint(device, &entries)?;
+        }
+        Ok(entries.iter().map(|entry| entry.length as usize).sum())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use device::{MemoryDevice};
+    use dir::{ROOT_INODE};
+    use fs::{FileSystem};
+    use super::*;
+
+    #[test]
+    fn replay_after_crash() {
+        let disk = MemoryDevice::new(256, Some(128)).unwrap();
+        let mut fs = FileSystem::new(disk.clone(), None).unwrap();
+        fs.mkdir(ROOT_INODE, "kept").unwrap();
+        fs.close().unwrap();
+        let mut fs = FileSystem::read(disk.clone()).unwrap().file_system;
+        let journal = fs.master_block.journal();
+        let block_map = BlockNumber::new(1);
+        let mut block = vec![0; 128];
+        fs.cache.device.read(block_map, &mut block).unwrap();
+        let logged = block.clone();
+        // Pretend the machine died halfway through checkpointing the block map
+        let slot = journal.block(1);
+        journal.log(&mut fs.cache.device, &[(block_map, logged.clone())], &[slot]).unwrap();
+        fs.cache.device.write(block_map, &mut [0; 128]).unwrap();
+        drop(fs);
+        let mount = FileSystem::read(disk.clone()).unwrap();
+        assert!(! mount.clean_mount);
+        assert_eq!(mount.replayed, 1);
+        let mut fs = mount.file_system;
+        fs.cache.device.read(block_map, &mut block).unwrap();
+        assert_eq!(block, logged);
+        assert!(fs.lookup(ROOT_INODE, "kept").unwrap().is_some());
+        assert_eq!(fs.master_block.journal().replay(&mut fs.cache.device).unwrap(), 0);
+    }
+
+    #[test]
+    fn oversized_transaction_is_refused() {
+        let mut disk = MemoryDevice::new(256, Some(128)).unwrap();
+        let journal = Journal::new(BlockNumber::new(100), 8);
+        let capacity = journal.capacity(128);
+        assert_eq!(capacity, 6);
+        let mut transaction = Transaction::new();
+        for i in 0 .. capacity as u64 + 1 {
+            transaction.write(BlockNumber::new(200 + i), vec![1; 128]);
+        }
+        match journal.commit(&mut disk, transaction) {
+            Err(Error::Size(_)) => {}
+            res => panic!("expected Size but got {:?}", res)
+        }
+        let mut block = vec![0; 128];
+        disk.read(BlockNumber::new(200), &mut block).unwrap();
+        assert_eq!(block, vec![0; 128]);
+    }
+
+    #[test]
+    fn torn_log_is_not_replayed() {
+        let mut disk = MemoryDevice::new(256, Some(128)).unwrap();
+        let journal = Journal::new(BlockNumber::new(100), 8);
+        let target = BlockNumber::new(200);
+        let slot = BlockNumber::new(101);
+        let mut block = vec![0; 128];
+        // A descriptor that only partly reached the disk is as good as none
+        journal.log(&mut disk, &[(target, vec![1; 128])], &[slot]).unwrap();
+        disk.read(BlockNumber::new(100), &mut block).unwrap();
+        block[20] ^= 0xff;
+        disk.write(BlockNumber::new(100), &mut block).unwrap();
+        assert_eq!(journal.replay(&mut disk).unwrap(), 0);
+        disk.read(target, &mut block).unwrap();
+        assert_eq!(block, vec![0; 128]);
+        // A logged block that does not match the descriptor is never copied
+        journal.log(&mut disk, &[(target, vec![1; 128])], &[slot]).unwrap();
+        disk.write(slot, &mut [2; 128]).unwrap();
+        match journal.replay(&mut disk) {
+            Err(Error::Corrupt { block, kind: "journal" }) => assert_eq!(block, slot),
+            res => panic!("expected Corrupt but got {:?}", res)
+        }
+        disk.read(target, &mut block).unwrap();
+        assert_eq!(block, vec![0; 128]);
+    }
+
+    #[test]
+    fn big_writes_commit_early() {
+        let disk = MemoryDevice::new(256, Some(128)).unwrap();
+        let mut fs = FileSystem::new(disk.clone(), None).unwrap();
+        let file = fs.create(ROOT_INODE, "big").unwrap();
+        let data = (0 .. 170 * 128).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        fs.write_at(file, 0, &data).unwrap();
+        // Together with the block map and the inodes the pointer blocks would not have fit
+        let pointer_blocks = fs.allocated_blocks(file).unwrap() - 170;
+        assert!(fs.cache.dirty_metadata() < pointer_blocks as usize);
+        fs.close().unwrap();
+        let mut fs = FileSystem::read(disk.clone()).unwrap().file_system;
+        let mut out = vec![0; data.len()];
+        fs.read_at(file, 0, &mut out).unwrap();
+        assert_eq!(out, data);
+        assert!(fs.fsck(false).unwrap().is_clean());
+    }
+}
@@ -1,9 +1,5 @@
 #[macro_use]
 extern crate nom;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
-extern crate bincode;
 #[macro_use]
 extern crate bitflags;
 extern crate bit_vec;
@@ -11,5 +7,15 @@ extern crate bit_vec;
 pub mod block_number;
 pub use block_number::BlockNumber;
 pub mod device;
+mod encoding;
+pub mod checksum;
+pub mod journal;
 pub mod cache;
 pub mod fs;
+pub mod extent;
+pub mod file;
+pub mod dir;
+pub mod path;
+pub mod fsck;
+pub mod resize;
+pub mod shared;
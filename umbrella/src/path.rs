@@ -0,0 +1,199 @@
+use device::{self, Error};
+use dir::{self, DirEntry, ROOT_INODE};
+use fs::{FileSystem, INodeFlags};
+
+/// How many symbolic links a single resolution may pass through before it is considered a loop.
+pub const MAX_SYMLINK_FOLLOWS : usize = 16;
+
+fn components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|component| ! component.is_empty()).collect()
+}
+
+impl FileSystem {
+    pub fn is_link(&self, inode_num: usize) -> bool {
+        self.inode_map.get(inode_num).flags.contains(INodeFlags::LINK)
+    }
+
+    /// Turns `path` into an inode number. Relative paths start at the directory `cwd`.
+    /// Symbolic links are followed, including the last component.
+    pub fn resolve(&mut self, cwd: usize, path: &str) -> device::Result<usize> {
+        let mut follows = 0;
+        self.walk(cwd, path, true, &mut follows)
+    }
+
+    /// Splits `path` into the directory that contains its last component and the name of that
+    /// component. The last component is not required to exist.
+    pub fn resolve_parent(&mut self, cwd: usize, path: &str) -> device::Result<(usize, String)> {
+        let (dir_path, name) = match path.trim_end_matches('/').rfind('/') {
+            Some(i) => (&path[.. i + 1], &path[i + 1 ..]),
+            None => ("", path)
+        };
+        let name = name.trim_end_matches('/');
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(Error::InvalidName(path.to_string()))
+        }
+        let parent = self.resolve(cwd, dir_path)?;
+        if ! self.is_dir(parent) {
+            return Err(Error::NotADirectory(dir_path.to_string()))
+        }
+        Ok((parent, name.to_string()))
+    }
+
+    fn walk(&mut self, cwd: usize, path: &str, follow_last: bool, follows: &mut usize)
+            -> device::Result<usize> {
+        let mut current = if path.starts_with('/') { ROOT_INODE } else { cwd };
+        let mut walked = if path.starts_with('/') { "/".to_string() } else { String::new() };
+        let components = components(path);
+        for (i, component) in components.iter().enumerate() {
+            if ! self.is_dir(current) {
+                return Err(Error::NotADirectory(walked))
+            }
+            if ! walked.is_empty() && ! walked.ends_with('/') {
+                walked.push('/');
+            }
+            walked.push_str(component);
+            let next = match self.lookup(current, component)? {
+                Some(next) => next,
+                None => return Err(Error::NotFound(walked))
+            };
+            let last = i + 1 == components.len();
+            if self.is_link(next) && (follow_last || ! last) {
+                *follows += 1;
+                if *follows > MAX_SYMLINK_FOLLOWS {
+                    return Err(Error::Loop(path.to_string()))
+                }
+                let target = self.read_link(next)?;
+                current = self.walk(current, &target, true, follows)?;
+            } else {
+                current = next;
+            }
+        }
+        Ok(current)
+    }
+
+    /// Creates a symbolic link `name` inside of `dir` that points at `target`.
+    pub fn symlink(&mut self, dir: usize, name: &str, target: &str) -> device::Result<usize> {
+        dir::validate_name(name)?;
+        if ! self.is_dir(dir) {
+            return Err(Error::NotADirectory(format!("inode {}", dir)))
+        }
+        if self.lookup(dir, name)?.is_some() {
+            return Err(Error::Exists(name.to_string()))
+        }
+        self.make_room(2)?;
+        let link = match self.inode_map.alloc(INodeFlags::LINK) {
+            Some(link) => link,
+            None => return Err(Error::Size("out of inodes".to_string()))
+        };
+        let linked = self.write_at(link, 0, target.as_bytes())
+            .and_then(|_| self.add_entry(dir, &DirEntry::new(link, name)));
+        match linked {
+            Ok(()) => Ok(link),
+            Err(err) => {
+                self.free_inode(link)?;
+                Err(err)
+            }
+        }
+    }
+
+    pub fn read_link(&mut self, link: usize) -> device::Result<String> {
+        let mut target = vec![0; self.inode_map.get(link).length as usize];
+        self.read_at(link, 0, &mut target)?;
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+
+    /// Rebuilds the absolute path of the directory `dir` by following `..` up to the root.
+    pub fn path_of(&mut self, dir: usize) -> device::Result<String> {
+        let mut names = vec![];
+        let mut current = dir;
+        while current != ROOT_INODE {
+            let parent = match self.lookup(current, "..")? {
+                Some(parent) => parent,
+                None => return Err(Error::NotFound(format!("inode {}/..", current)))
+            };
+            let entry = self.read_dir(parent)?
+                .into_iter()
+                .find(|entry| entry.inode == current && entry.name != "." && entry.name != "..");
+            match entry {
+                Some(entry) => names.push(entry.name),
+                None => return Err(Error::NotFound(format!("inode {}", current)))
+            }
+            current = parent;
+        }
+        names.reverse();
+        Ok(format!("/{}", names.join("/")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use device::MemoryDevice;
+    use super::*;
+
+    fn fixture() -> (FileSystem, usize, usize) {
+        let device = MemoryDevice::new(256, Some(128)).unwrap();
+        let mut fs = FileSystem::new(device, None).unwrap();
+        let projects = fs.mkdir(ROOT_INODE, "projects").unwrap();
+        let beach = fs.mkdir(projects, "beach").unwrap();
+        (fs, projects, beach)
+    }
+
+    #[test]
+    fn resolve_absolute_and_relative() {
+        let (mut fs, projects, beach) = fixture();
+        assert_eq!(fs.resolve(beach, "/").unwrap(), ROOT_INODE);
+        assert_eq!(fs.resolve(ROOT_INODE, "/projects/beach").unwrap(), beach);
+        assert_eq!(fs.resolve(projects, "beach/").unwrap(), beach);
+        assert_eq!(fs.resolve(beach, "..").unwrap(), projects);
+        assert_eq!(fs.resolve(beach, "../../..").unwrap(), ROOT_INODE);
+        assert_eq!(fs.resolve(beach, "./.././beach").unwrap(), beach);
+        assert_eq!(fs.path_of(beach).unwrap(), "/projects/beach");
+    }
+
+    #[test]
+    fn resolve_errors() {
+        let (mut fs, projects, _) = fixture();
+        let link = fs.symlink(projects, "notes.txt", "/missing").unwrap();
+        match fs.resolve(ROOT_INODE, "/projects/nope") {
+            Err(Error::NotFound(path)) => assert_eq!(path, "/projects/nope"),
+            res => panic!("expected NotFound but got {:?}", res)
+        }
+        fs.inode_map.get_mut(link).flags = INodeFlags::FILE;
+        match fs.resolve(ROOT_INODE, "/projects/notes.txt/x") {
+            Err(Error::NotADirectory(path)) => assert_eq!(path, "/projects/notes.txt"),
+            res => panic!("expected NotADirectory but got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn resolve_symlinks() {
+        let (mut fs, projects, beach) = fixture();
+        fs.symlink(ROOT_INODE, "b", "projects/beach").unwrap();
+        assert_eq!(fs.resolve(ROOT_INODE, "/b/..").unwrap(), projects);
+        assert_eq!(fs.resolve(projects, "/b").unwrap(), beach);
+        fs.symlink(ROOT_INODE, "ping", "pong").unwrap();
+        fs.symlink(ROOT_INODE, "pong", "/ping").unwrap();
+        match fs.resolve(ROOT_INODE, "ping") {
+            Err(Error::Loop(_)) => {}
+            res => panic!("expected Loop but got {:?}", res)
+        }
+        assert_eq!(fs.resolve_parent(beach, "../x").unwrap(), (projects, "x".to_string()));
+    }
+
+    #[test]
+    fn failed_symlink_frees_its_inode() {
+        let (mut fs, projects, _) = fixture();
+        match fs.symlink(projects, "a_name_longer_than_a_directory_slot", "/") {
+            Err(Error::InvalidName(_)) => {}
+            res => panic!("expected InvalidName but got {:?}", res)
+        }
+        // The target needs a data block that cannot be allocated
+        while fs.block_map.alloc().is_ok() {}
+        match fs.symlink(projects, "nowhere", "/projects") {
+            Err(Error::Size(_)) => {}
+            res => panic!("expected Size but got {:?}", res)
+        }
+        assert_eq!(fs.inode_map.get(projects + 2).flags, INodeFlags::FREE);
+        assert_eq!(fs.lookup(projects, "nowhere").unwrap(), None);
+    }
+}
@@ -0,0 +1,428 @@
+use std::cmp::{max};
+
+use block_number::{BlockNumber, MASTER_BLOCK_NUMBER, Sequence};
+use cache::{Cache};
+use device::{self, Error};
+use extent::{self, Extent};
+use fs::{BlockMap, FileSystem, INodeFlags, INodeFormat, MasterBlockFlags};
+
+// Resizing keeps the layout of `MasterBlock::new`. The block map takes as many blocks as the new
+// size needs and the inode table and the journal follow right after it, so when the map grows
+// they move up into what used to be the data region. A resize goes through two steps that each
+// leave a consistent file system behind:
+//
+// 1. Every block an inode holds there, at a new backup location, or past the new end of the
+//    device is copied somewhere else and the pointer to it is updated. This commits through the
+//    journal like any other write. The blocks that are cleared out are held in the block map so
+//    that nothing is allocated there while the old layout still has them as free.
+// 2. The new master block, block map, and inode table are staged in blocks that neither layout
+//    uses and committed as a single transaction, see `Journal::stage`. Checkpointing it copies
+//    them into place and writes the master block last, which is when the new layout takes over.
+//
+// The descriptor of that transaction goes into whichever journal is not overwritten by it. When
+// the journal moves up the old one ends up under the new inode table, so the descriptor goes into
+// the new journal and the new master block is written before the checkpoint to point at it.
+
+// Copies `block_num` to a freshly allocated block and holds on to the old copy.
+fn relocate(block_map: &mut BlockMap, cache: &mut Cache, block_num: BlockNumber, pointers: bool)
+            -> device::Result<BlockNumber> {
+    let new_block_num = block_map.alloc()?;
+    if pointers {
+        let children = cache.read_pointers(block_num)?.to_vec();
+        cache.write_pointers(new_block_num, &children)?;
+    } else {
+        let block = cache.read(block_num)?.borrow().clone();
+        cache.write(new_block_num, block)?;
+    }
+    cache.forget(block_num);
+    block_map.hold(block_num);
+    Ok(new_block_num)
+}
+
+impl FileSystem {
+    /// Grows or shrinks the file system and its device to `block_count` blocks. Blocks that are
+    /// in the way of the new layout are moved first and a shrink that would leave too few free
+    /// blocks for them is refused before anything changes. The new layout replaces the old one
+    /// in a single journal transaction, so a crash leaves one or the other behind.
+    pub fn resize(&mut self, block_count: u64) -> device::Result<()> {
+        let block_size = self.cache.device.config().block_size;
+        let old = self.master_block.clone();
+        let mut new = old.resized(block_count);
+        new.flags.set(MasterBlockFlags::SYNCED, false);
+        if new.data_start().number >= block_count {
+            let err_msg = format!(
+                "resizefs: block_count [{}] is too small to hold [{}] inodes",
+                block_count,
+                new.inode_count()
+            );
+            return Err(Error::Size(err_msg))
+        }
+        let old_count = old.block_count();
+        let vacate = |block_num: BlockNumber| {
+            block_num.number >= block_count
+                || (new.is_reserved(block_num) && ! old.is_reserved(block_num))
+        };
+        let mut in_use = 0;
+        let mut free = 0;
+        // Free blocks that neither layout uses, and the blocks past the new end that are free
+        // once the relocation is done
+        let mut spare = vec![];
+        let mut past_end = vec![];
+        for block_num in Sequence::new(MASTER_BLOCK_NUMBER, max(old_count, block_count)) {
+            let allocated = self.block_map.is_allocated(block_num);
+            if vacate(block_num) {
+                if allocated && ! old.is_reserved(block_num) {
+                    in_use += 1;
+                }
+                if block_num.number >= block_count && ! old.is_reserved(block_num) {
+                    past_end.push(block_num);
+                }
+            } else if ! allocated && ! old.is_reserved(block_num) {
+                if block_num.number < old_count {
+                    free += 1;
+                }
+                spare.push(block_num);
+            }
+        }
+        if in_use > free {
+            let err_msg = format!(
+                "resizefs: [{}] blocks are in the way of block_count [{}] but only [{}] are free",
+                in_use,
+                block_count,
+                free
+            );
+            return Err(Error::Size(err_msg))
+        }
+        // Backups that the old layout has a use for, its journal say, are only written at the end
+        let backups = new.backups()
+            .into_iter()
+            .filter(|block_num| ! old.is_reserved(*block_num))
+            .collect::<Vec<_>>();
+        // The master block, the block map, the inode table, the backups, and a cleared journal
+        // descriptor when the journal moves down
+        let staged = new.journal().start().number as usize
+            + backups.len()
+            + (new.data_start() < old.data_start()) as usize;
+        if in_use + staged.saturating_sub(past_end.len()) > spare.len() {
+            let err_msg = format!(
+                "resizefs: [{}] blocks are in the way of block_count [{}] and [{}] are needed to \
+                 stage the new metadata but only [{}] are free",
+                in_use,
+                block_count,
+                staged,
+                spare.len() + past_end.len()
+            );
+            return Err(Error::Size(err_msg))
+        }
+        // Staging past the new end first and then from the top keeps the free blocks of the old
+        // device for the relocations
+        past_end.truncate(staged);
+        let mut staging = spare.split_off(spare.len() + past_end.len() - staged);
+        staging.extend(past_end);
+        // The backups are the only targets that are not all in a row
+        let runs = 1 + staging.windows(2).filter(|w| w[0].number + 1 != w[1].number).count()
+            + backups.len();
+        if runs > old.journal().max_runs(block_size) {
+            let err_msg = format!(
+                "resizefs: the free blocks are too fragmented to stage the new metadata in [{}] \
+                 runs",
+                old.journal().max_runs(block_size)
+            );
+            return Err(Error::Size(err_msg))
+        }
+        self.write()?;
+        if block_count > old_count {
+            self.cache.device.resize(block_count)?;
+        }
+        // Nothing may be allocated where the blocks are moving away from or where the new
+        // metadata is staged
+        for block_num in Sequence::new(MASTER_BLOCK_NUMBER, old_count) {
+            if vacate(block_num) && ! self.block_map.is_allocated(block_num) {
+                self.block_map.hold(block_num);
+            }
+        }
+        for block_num in staging.iter().filter(|block_num| block_num.number < old_count) {
+            self.block_map.hold(*block_num);
+        }
+        for inode_num in 0 .. old.inode_count() as usize {
+            if self.inode_map.get(inode_num).flags.contains(INodeFlags::FREE) {
+                continue
+            }
+            if old.format() == INodeFormat::Extents {
+                self.relocate_extents(inode_num, &vacate)?;
+            } else {
+                self.relocate_tree(inode_num, &vacate)?;
+            }
+        }
+        self.write()?;
+        self.block_map.release();
+        self.block_map.resize(block_count);
+        for block_num in Sequence::new(MASTER_BLOCK_NUMBER, block_count) {
+            if new.is_reserved(block_num) {
+                self.block_map.set(block_num, true);
+            } else if old.is_reserved(block_num) {
+                self.block_map.set(block_num, false);
+            }
+        }
+        self.inode_map.mark_dirty();
+        self.master_block = new.clone();
+        let mut transaction = self.transaction()?;
+        let mut mb_vec = vec![0; block_size as usize];
+        new.encode(&mut mb_vec);
+        transaction.write(MASTER_BLOCK_NUMBER, mb_vec);
+        // Backups are written the way `close` writes them
+        let mut backup = new.clone();
+        backup.flags.set(MasterBlockFlags::SYNCED, true);
+        let mut mb_vec = vec![0; block_size as usize];
+        backup.encode(&mut mb_vec);
+        for block_num in backups {
+            transaction.write(block_num, mb_vec.clone());
+        }
+        let moves_up = new.data_start() > old.data_start();
+        if new.data_start() < old.data_start() {
+            // What ends up where the new journal starts must not pass for a descriptor
+            transaction.write(new.journal().start(), vec![0; block_size as usize]);
+        }
+        let journal = if moves_up { new.journal() } else { old.journal() };
+        journal.stage(&mut *self.cache.device, transaction, &staging)?;
+        if moves_up {
+            new.write(&mut *self.cache.device)?;
+            self.cache.device.sync()?;
+        }
+        journal.replay(&mut *self.cache.device)?;
+        self.block_map.clean();
+        self.inode_map.clean();
+        backup.write_backups(&mut *self.cache.device)?;
+        self.cache.device.sync()?;
+        if block_count < old_count {
+            self.cache.device.resize(block_count)?;
+        }
+        Ok(())
+    }
+
+    fn relocate_tree(&mut self, inode_num: usize, vacate: &dyn Fn(BlockNumber) -> bool)
+                     -> device::Result<()> {
+        let (block_ptrs, level) = {
+            let inode = self.inode_map.get(inode_num);
+            (inode.block_ptrs, inode.level)
+        };
+        for (i, block_ptr) in block_ptrs.iter().enumerate() {
+            if *block_ptr == MASTER_BLOCK_NUMBER {
+                continue
+            }
+            let mut block_ptr = *block_ptr;
+            if vacate(block_ptr) {
+                // The moved block and the inode
+                self.make_room(2)?;
+                block_ptr = relocate(&mut self.block_map, &mut self.cache, block_ptr, level > 0)?;
+                self.inode_map.get_mut(inode_num).block_ptrs[i] = block_ptr;
+            }
+            if level > 0 {
+                self.relocate_node(block_ptr, level - 1, vacate)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Moves the children of the pointer block `node` that are in the way, committing whenever
+    // the journal fills up.
+    fn relocate_node(&mut self, node: BlockNumber, level: u8, vacate: &dyn Fn(BlockNumber) -> bool)
+                     -> device::Result<()> {
+        let children = self.cache.read_pointers(node)?.to_vec();
+        for (i, child) in children.into_iter().enumerate() {
+            if child == MASTER_BLOCK_NUMBER {
+                continue
+            }
+            let mut child = child;
+            if vacate(child) {
+                // The moved block and `node`
+                self.make_room(2)?;
+                child = relocate(&mut self.block_map, &mut self.cache, child, level > 0)?;
+                self.cache.read_pointers_mut(node)?.set(i, child);
+            }
+            if level > 0 {
+                self.relocate_node(child, level - 1, vacate)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn relocate_extents(&mut self, inode_num: usize, vacate: &dyn Fn(BlockNumber) -> bool)
+                        -> device::Result<()> {
+        let (extents, nodes) = self.read_extents(inode_num)?;
+        let moving = extents.iter().flat_map(|extent| extent.blocks()).any(vacate)
+            || nodes.iter().any(|node| vacate(*node));
+        if ! moving {
+            return Ok(())
+        }
+        self.make_room(nodes.len() + 1)?;
+        let mut moved = vec![];
+        for extent in extents {
+            for (i, block_num) in extent.blocks().into_iter().enumerate() {
+                let start = if vacate(block_num) {
+                    relocate(&mut self.block_map, &mut self.cache, block_num, false)?
+                } else {
+                    block_num
+                };
+                moved.push(Extent { logical: extent.logical + i as u64, start, length: 1 });
+            }
+        }
+        extent::coalesce(&mut moved);
+        // The nodes in the way are rebuilt elsewhere by `write_extents`
+        let (gone, kept) : (Vec<_>, Vec<_>) = nodes.into_iter().partition(|node| vacate(*node));
+        for node in gone {
+            self.cache.forget(node);
+            self.block_map.hold(node);
+        }
+        self.write_extents(inode_num, &moved, kept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use device::{BlockDevice, Counters, DeviceConfig, MemoryDevice};
+    use dir::{ROOT_INODE};
+    use std::io;
+    use super::*;
+
+    // A device that loses power once `budget` blocks were written. Nothing written after that
+    // reaches the disk.
+    struct PowerCut {
+        disk:   MemoryDevice,
+        budget: usize
+    }
+
+    impl BlockDevice for PowerCut {
+        fn config(&self) -> &DeviceConfig {
+            self.disk.config()
+        }
+
+        fn read(&self, block_num: BlockNumber, buf: &mut [u8]) -> device::Result<()> {
+            self.disk.read(block_num, buf)
+        }
+
+        fn write(&mut self, block_num: BlockNumber, buf: &mut [u8]) -> device::Result<()> {
+            if self.budget == 0 {
+                return Err(Error::IO(io::Error::other("power cut")))
+            }
+            self.budget -= 1;
+            self.disk.write(block_num, buf)
+        }
+
+        fn sync(&mut self) -> device::Result<()> {
+            self.disk.sync()
+        }
+
+        fn counters(&self) -> Counters {
+            self.disk.counters()
+        }
+
+        fn resize(&mut self, block_count: u64) -> device::Result<()> {
+            self.disk.resize(block_count)
+        }
+    }
+
+    fn fill(fs: &mut FileSystem, name: &str, blocks: u64) -> (usize, Vec<u8>) {
+        let file = fs.create(ROOT_INODE, name).unwrap();
+        let data = (0 .. blocks * 128).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        fs.write_at(file, 0, &data).unwrap();
+        (file, data)
+    }
+
+    fn check(fs: &mut FileSystem, file: usize, data: &[u8]) {
+        let mut out = vec![0; data.len()];
+        fs.read_at(file, 0, &mut out).unwrap();
+        assert_eq!(out, data);
+        assert!(fs.fsck(false).unwrap().is_clean());
+    }
+
+    fn grow_moves_metadata(format: INodeFormat) {
+        // A block map block covers 992 blocks of 128 bytes
+        let disk = MemoryDevice::new(900, Some(128)).unwrap();
+        let mut fs = FileSystem::new_with_format(disk.clone(), None, format).unwrap();
+        let data_start = fs.master_block.data_start();
+        let (file, data) = fill(&mut fs, "first", 40);
+        fs.resize(3000).unwrap();
+        assert_eq!(fs.master_block.block_map_blocks(), 4);
+        assert_eq!(fs.master_block.data_start().number, data_start.number + 3);
+        assert_eq!(fs.cache.device.config().block_count, 3000);
+        check(&mut fs, file, &data);
+        let (second, more) = fill(&mut fs, "second", 1500);
+        fs.close().unwrap();
+        let mut fs = FileSystem::read(disk.clone()).unwrap().file_system;
+        assert_eq!(fs.master_block.block_count(), 3000);
+        check(&mut fs, file, &data);
+        check(&mut fs, second, &more);
+    }
+
+    #[test]
+    fn grow_tree() {
+        grow_moves_metadata(INodeFormat::Tree);
+    }
+
+    #[test]
+    fn grow_extents() {
+        grow_moves_metadata(INodeFormat::Extents);
+    }
+
+    // Cuts the power after every single block a resize from `from` to `to` blocks writes and
+    // checks that the file system comes back in one layout or the other with its data intact.
+    fn resize_survives_power_cuts(from: u64, to: u64, freed: u64) {
+        let image = MemoryDevice::new(from, Some(128)).unwrap();
+        let mut fs = FileSystem::new(image.clone(), Some(32)).unwrap();
+        let (gone, _) = fill(&mut fs, "gone", freed);
+        let (file, data) = fill(&mut fs, "kept", 40);
+        fs.truncate(gone, 0).unwrap();
+        fs.close().unwrap();
+        let mut blocks = vec![0; from as usize * 128];
+        image.read_blocks(MASTER_BLOCK_NUMBER, from, &mut blocks).unwrap();
+        // Mounting takes one write
+        for budget in 1 .. {
+            let mut disk = MemoryDevice::new(from, Some(128)).unwrap();
+            disk.write_blocks(MASTER_BLOCK_NUMBER, from, &blocks).unwrap();
+            let power_cut = PowerCut { disk: disk.clone(), budget };
+            let mut fs = FileSystem::read(power_cut).unwrap().file_system;
+            let resized = fs.resize(to).is_ok();
+            drop(fs);
+            let mut fs = FileSystem::read(disk.clone()).unwrap().file_system;
+            let block_count = fs.master_block.block_count();
+            assert!(block_count == from || block_count == to);
+            check(&mut fs, file, &data);
+            if resized {
+                assert_eq!(block_count, to);
+                break
+            }
+        }
+    }
+
+    #[test]
+    fn grow_survives_power_cuts() {
+        resize_survives_power_cuts(900, 3000, 0);
+    }
+
+    #[test]
+    fn shrink_survives_power_cuts() {
+        resize_survives_power_cuts(1200, 700, 640);
+    }
+
+    #[test]
+    fn shrink_moves_blocks_past_the_end() {
+        let disk = MemoryDevice::new(2048, Some(128)).unwrap();
+        let mut fs = FileSystem::new(disk.clone(), None).unwrap();
+        let (first, data) = fill(&mut fs, "first", 1200);
+        let (second, more) = fill(&mut fs, "second", 100);
+        fs.truncate(first, 0).unwrap();
+        fs.resize(700).unwrap();
+        assert_eq!(fs.master_block.block_map_blocks(), 1);
+        assert_eq!(fs.master_block.backups(), vec![BlockNumber::new(699)]);
+        check(&mut fs, second, &more);
+        assert!(fs.resize(450).is_err());
+        assert_eq!(fs.master_block.block_count(), 700);
+        fs.close().unwrap();
+        assert_eq!(disk.clone().config().block_count, 700);
+        let mut fs = FileSystem::read(disk.clone()).unwrap().file_system;
+        check(&mut fs, second, &more);
+        fs.write_at(first, 0, &data[.. 128 * 150]).unwrap();
+        check(&mut fs, first, &data[.. 128 * 150]);
+    }
+}
@@ -0,0 +1,235 @@
+use std::cmp::{min};
+use std::io::{Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockWriteGuard};
+
+use device;
+use file::{gather, pieces, scatter};
+use fs::{FileSystem};
+
+/// A handle to a mounted file system that can be cloned and used from several threads at once.
+///
+/// Every inode has a lock of its own which `read_at`, `write_at`, and `truncate` hold for their
+/// whole call so that they are atomic with respect to each other. The file system itself is
+/// only locked while blocks are looked up or allocated, the bytes are then copied in and out
+/// of the cached blocks under the lock of each block. Threads working on different files only
+/// wait for each other while they look up blocks. Everything else goes through `lock`, which
+/// holds the lock of every inode as well.
+#[derive(Clone)]
+pub struct SharedFileSystem {
+    fs:     Arc<Mutex<FileSystem>>,
+    inodes: Arc<Vec<RwLock<()>>>
+}
+
+impl SharedFileSystem {
+    pub fn new(fs: FileSystem) -> SharedFileSystem {
+        let inodes = (0 .. fs.master_block.inode_count()).map(|_| RwLock::new(())).collect();
+        SharedFileSystem { fs: Arc::new(Mutex::new(fs)), inodes: Arc::new(inodes) }
+    }
+
+    /// Locks the whole file system for everything that the other methods do not cover. The lock
+    /// of every inode is taken first, so nothing done through it, freeing an inode say, can ever
+    /// interleave with a `read_at`, `write_at`, or `truncate` that is under way.
+    pub fn lock(&self) -> FileSystemGuard<'_> {
+        let inodes = self.inodes.iter().map(|inode| inode.write().unwrap()).collect();
+        FileSystemGuard { fs: self.fs(), _inodes: inodes }
+    }
+
+    // Locks only the file system itself, for callers that hold the lock of their inode.
+    fn fs(&self) -> MutexGuard<'_, FileSystem> {
+        self.fs.lock().unwrap()
+    }
+
+    /// Like `FileSystem::read_at`.
+    pub fn read_at(&self, inode_num: usize, offset: u64, buf: &mut [u8]) -> device::Result<usize> {
+        let _inode = self.inodes[inode_num].read().unwrap();
+        let (block_size, length) = {
+            let fs = self.fs();
+            (fs.cache.device.config().block_size as u64, fs.inode_map.get(inode_num).length)
+        };
+        if offset >= length {
+            return Ok(0)
+        }
+        let total = min(buf.len() as u64, length - offset) as usize;
+        for (position, range) in pieces(offset, total, block_size) {
+            let blocks = self.fs().data_blocks(inode_num, position, range.len())?;
+            gather(&blocks, (position % block_size) as usize, block_size as usize, &mut buf[range]);
+        }
+        Ok(total)
+    }
+
+    /// Like `FileSystem::write_at`.
+    pub fn write_at(&self, inode_num: usize, offset: u64, buf: &[u8]) -> device::Result<usize> {
+        let _inode = self.inodes[inode_num].write().unwrap();
+        let block_size = self.fs().cache.device.config().block_size as u64;
+        for (position, range) in pieces(offset, buf.len(), block_size) {
+            let blocks = self.fs().data_blocks_mut(inode_num, position, range.len())?;
+            scatter(&blocks, (position % block_size) as usize, block_size as usize, &buf[range]);
+            // A `FileSystem::write` in the meantime may have marked the blocks clean
+            let mut fs = self.fs();
+            for (block_num, _) in blocks {
+                fs.cache.mark_dirty(block_num);
+            }
+        }
+        self.fs().wrote(inode_num, offset + buf.len() as u64);
+        Ok(buf.len())
+    }
+
+    /// Like `FileSystem::truncate`.
+    pub fn truncate(&self, inode_num: usize, length: u64) -> device::Result<()> {
+        let _inode = self.inodes[inode_num].write().unwrap();
+        self.fs().truncate(inode_num, length)
+    }
+
+    /// Like `FileSystem::copy_in`, the file system is only locked while blocks are looked up.
+    pub fn copy_in<R: Read>(&self, inode_num: usize, reader: &mut R) -> device::Result<u64> {
+        let (block_size, start) = {
+            let fs = self.fs();
+            (fs.cache.device.config().block_size as usize, fs.inode_map.get(inode_num).length)
+        };
+        let mut buf = vec![0; block_size];
+        let mut offset = start;
+        loop {
+            let read = reader.read(&mut buf)?;
+            if read == 0 {
+                return Ok(offset - start)
+            }
+            offset += self.write_at(inode_num, offset, &buf[.. read])? as u64;
+        }
+    }
+
+    /// Like `FileSystem::copy_out`, the file system is only locked while blocks are looked up.
+    pub fn copy_out<W: Write>(&self, inode_num: usize, writer: &mut W) -> device::Result<u64> {
+        let mut buf = vec![0; self.fs().cache.device.config().block_size as usize];
+        let mut offset = 0;
+        loop {
+            let read = self.read_at(inode_num, offset, &mut buf)?;
+            if read == 0 {
+                return Ok(offset)
+            }
+            writer.write_all(&buf[.. read])?;
+            offset += read as u64;
+        }
+    }
+
+    /// Gives the file system back once this is the last handle to it.
+    pub fn into_inner(self) -> Result<FileSystem, SharedFileSystem> {
+        let SharedFileSystem { fs, inodes } = self;
+        match Arc::try_unwrap(fs) {
+            Ok(fs) => Ok(fs.into_inner().unwrap()),
+            Err(fs) => Err(SharedFileSystem { fs, inodes })
+        }
+    }
+}
+
+/// Exclusive access to a shared file system, see `SharedFileSystem::lock`.
+pub struct FileSystemGuard<'a> {
+    // Dropped before the inode locks so that nobody waits on the file system with one of them
+    fs:      MutexGuard<'a, FileSystem>,
+    _inodes: Vec<RwLockWriteGuard<'a, ()>>
+}
+
+impl<'a> Deref for FileSystemGuard<'a> {
+    type Target = FileSystem;
+
+    fn deref(&self) -> &FileSystem {
+        &self.fs
+    }
+}
+
+impl<'a> DerefMut for FileSystemGuard<'a> {
+    fn deref_mut(&mut self) -> &mut FileSystem {
+        &mut self.fs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use device::{MemoryDevice};
+    use dir::{ROOT_INODE};
+    use super::*;
+
+    #[test]
+    fn handles_are_send_and_sync() {
+        fn check<T: Send + Sync>() {}
+        check::<FileSystem>();
+        check::<SharedFileSystem>();
+    }
+
+    #[test]
+    fn workers_share_a_file_system() {
+        let disk = MemoryDevice::new(4096, Some(128)).unwrap();
+        let mut fs = FileSystem::new(disk.clone(), None).unwrap();
+        fs.set_cache_capacity(16).unwrap();
+        let shared = SharedFileSystem::new(fs);
+        let files = (0 .. 4)
+            .map(|i| shared.lock().create(ROOT_INODE, &format!("file{}", i)).unwrap())
+            .collect::<Vec<_>>();
+        let workers = files.iter().enumerate().map(|(i, file)| {
+            let (shared, file) = (shared.clone(), *file);
+            thread::spawn(move || {
+                for n in 0 .. 40u64 {
+                    shared.write_at(file, n * 100, &[i as u8 + 1; 100]).unwrap();
+                    if n % 10 == 0 {
+                        shared.lock().write().unwrap();
+                    }
+                }
+            })
+        }).collect::<Vec<_>>();
+        // A reader of the same file only ever sees whole writes
+        let reader = {
+            let (shared, file) = (shared.clone(), files[0]);
+            thread::spawn(move || {
+                let mut buf = vec![0; 4000];
+                for _ in 0 .. 40 {
+                    let read = shared.read_at(file, 0, &mut buf).unwrap();
+                    assert_eq!(read % 100, 0);
+                    assert!(buf[.. read].iter().all(|b| *b == 1));
+                }
+            })
+        };
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        reader.join().unwrap();
+        shared.truncate(files[3], 50).unwrap();
+        let fs = shared.into_inner().ok().unwrap();
+        fs.close().unwrap();
+        let shared = SharedFileSystem::new(FileSystem::read(disk.clone()).unwrap().file_system);
+        for (i, file) in files.iter().enumerate() {
+            let mut out = vec![0; 4000];
+            let expected = if i == 3 { 50 } else { 4000 };
+            assert_eq!(shared.read_at(*file, 0, &mut out).unwrap(), expected);
+            assert!(out[.. expected].iter().all(|b| *b == i as u8 + 1));
+        }
+        let mut out = vec![];
+        assert_eq!(shared.copy_out(files[3], &mut out).unwrap(), 50);
+        assert_eq!(shared.copy_in(files[3], &mut &[4; 30][..]).unwrap(), 30);
+        assert_eq!(shared.read_at(files[3], 0, &mut out).unwrap(), 50);
+        assert_eq!(shared.lock().inode_map.get(files[3]).length, 80);
+        assert!(shared.lock().fsck(false).unwrap().is_clean());
+    }
+    #[test]
+    fn locking_the_file_system_waits_for_readers() {
+        let disk = MemoryDevice::new(1024, Some(128)).unwrap();
+        let shared = SharedFileSystem::new(FileSystem::new(disk, None).unwrap());
+        let file = shared.lock().create(ROOT_INODE, "file").unwrap();
+        shared.write_at(file, 0, &[0; 2000]).unwrap();
+        let reader = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let mut buf = vec![0; 2000];
+                for _ in 0 .. 1000 {
+                    shared.read_at(file, 0, &mut buf).unwrap();
+                    assert!(buf.iter().all(|b| *b == buf[0]));
+                }
+            })
+        };
+        // Writes through the raw file system never show up halfway in a read
+        for n in 0 .. 2000 {
+            shared.lock().write_at(file, 0, &[n as u8; 2000]).unwrap();
+        }
+        reader.join().unwrap();
+    }
+}